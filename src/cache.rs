@@ -0,0 +1,311 @@
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use reqwest::header::{self, HeaderMap};
+use std::fmt;
+
+/// Which header said how long the response stays fresh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    MaxAge,
+    SMaxAge,
+    Expires,
+}
+
+/// What the server said about caching a response, from `Cache-Control`, `Expires` and `Age`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CachePolicy {
+    /// `max-age`, in seconds.
+    pub max_age: Option<i64>,
+    /// `s-maxage`, in seconds. Meant for shared caches, which is what we are to the subscribers.
+    pub s_maxage: Option<i64>,
+    /// Seconds from the response's `Date` (or our clock, without one) until `Expires`.
+    pub expires: Option<i64>,
+    /// `Age`, in seconds, for responses which already sat in some cache along the way.
+    pub age: i64,
+    /// `no-cache` without field names, so the response must be revalidated before every use.
+    pub no_cache: bool,
+    pub no_store: bool,
+}
+
+impl Source {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::MaxAge => "max-age",
+            Self::SMaxAge => "s-maxage",
+            Self::Expires => "expires",
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Split on commas which are not inside a quoted string.
+fn split_directives(value: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                result.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(&value[start..]);
+    result
+}
+
+/// Remove the quotes around a value (and the escapes inside them), if it has any.
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(inner) => {
+            let mut result = String::with_capacity(inner.len());
+            let mut escaped = false;
+            for c in inner.chars() {
+                if c == '\\' && !escaped {
+                    escaped = true;
+                } else {
+                    result.push(c);
+                    escaped = false;
+                }
+            }
+            result
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Parse an HTTP date in the preferred format or either of the obsolete ones.
+fn parse_date(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    DateTime::parse_from_rfc2822(value)
+        .map(DateTime::<Utc>::from)
+        .ok()
+        .or_else(|| {
+            // RFC 850, like `Sunday, 06-Nov-94 08:49:37 GMT`, and asctime, like `Sun Nov  6 08:49:37 1994`.
+            ["%A, %d-%b-%y %H:%M:%S GMT", "%a %b %e %H:%M:%S %Y"]
+                .iter()
+                .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+                .map(|date| DateTime::<Utc>::from_utc(date, Utc))
+        })
+}
+
+/// Parse `delta-seconds`, which are never negative.
+fn parse_seconds(value: &str) -> Option<i64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // Too large to fit means "practically forever".
+    Some(value.parse::<i64>().unwrap_or(i64::MAX))
+}
+
+impl CachePolicy {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        Self::parse_at(headers, Utc::now())
+    }
+
+    /// Parse the headers as if they were received at `now`.
+    ///
+    /// Anything malformed is ignored, as if the server had left it out, except for an invalid
+    /// `Expires`, which means the response is already stale.
+    fn parse_at(headers: &HeaderMap, now: DateTime<Utc>) -> Self {
+        let mut policy = Self::default();
+
+        // The header may be split across several lines.
+        for value in headers.get_all(header::CACHE_CONTROL) {
+            let value = match value.to_str() {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            for directive in split_directives(value) {
+                let mut parts = directive.splitn(2, '=');
+                let key = parts.next().unwrap_or("").trim().to_lowercase();
+                let value = parts.next().map(|value| unquote(value.trim()));
+                match (key.as_str(), value) {
+                    ("max-age", Some(value)) => {
+                        policy.max_age = policy.max_age.or_else(|| parse_seconds(&value))
+                    }
+                    ("s-maxage", Some(value)) => {
+                        policy.s_maxage = policy.s_maxage.or_else(|| parse_seconds(&value))
+                    }
+                    // With field names, only those fields are affected, not the response itself.
+                    ("no-cache", None) => policy.no_cache = true,
+                    ("no-store", _) => policy.no_store = true,
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(expires) = headers.get(header::EXPIRES) {
+            let date = headers
+                .get(header::DATE)
+                .and_then(|date| date.to_str().ok())
+                .and_then(parse_date)
+                .unwrap_or(now);
+            policy.expires = Some(
+                expires
+                    .to_str()
+                    .ok()
+                    .and_then(parse_date)
+                    .map_or(0, |expires| (expires - date).num_seconds().max(0)),
+            );
+        }
+
+        policy.age = headers
+            .get(header::AGE)
+            .and_then(|age| age.to_str().ok())
+            .and_then(|age| parse_seconds(age.trim()))
+            .unwrap_or(0);
+
+        policy
+    }
+
+    /// How much longer the response stays fresh, and which header said so.
+    ///
+    /// `None` if the server gave no lifetime, or a response that has to be revalidated every time.
+    /// That forbids reusing the content without asking, but says nothing about how often to ask,
+    /// so it's left for the feed's own schedule to decide.
+    pub fn freshness(&self) -> Option<(Duration, Source)> {
+        if self.no_cache || self.no_store {
+            return None;
+        }
+        let (lifetime, source) = if let Some(s_maxage) = self.s_maxage {
+            (s_maxage, Source::SMaxAge)
+        } else if let Some(max_age) = self.max_age {
+            (max_age, Source::MaxAge)
+        } else {
+            (self.expires?, Source::Expires)
+        };
+        let remaining = lifetime.saturating_sub(self.age);
+        if remaining > 0 {
+            // Beyond what `Duration` can hold, which is millennia anyway.
+            Some((Duration::seconds(remaining.min(i64::MAX / 1000)), source))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use reqwest::header::HeaderValue;
+
+    fn policy(headers: &[(header::HeaderName, &str)]) -> CachePolicy {
+        let mut map = HeaderMap::new();
+        for (key, value) in headers {
+            map.append(key.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        // The same instant as the `Date` used below.
+        CachePolicy::parse_at(&map, Utc.ymd(2021, 5, 1).and_hms(0, 0, 0))
+    }
+
+    fn seconds(policy: CachePolicy) -> Option<(i64, Source)> {
+        policy
+            .freshness()
+            .map(|(delay, source)| (delay.num_seconds(), source))
+    }
+
+    #[test]
+    fn check_cache_control_parse() {
+        use header::CACHE_CONTROL as CC;
+
+        let cases: &[(&[&str], Option<(i64, Source)>)] = &[
+            (&["public, max-age=300"], Some((300, Source::MaxAge))),
+            (&["max-age=300,public"], Some((300, Source::MaxAge))),
+            (&["MAX-AGE = 300"], Some((300, Source::MaxAge))),
+            (&["max-age=\"300\""], Some((300, Source::MaxAge))),
+            (&["public", "max-age=300"], Some((300, Source::MaxAge))),
+            (&["max-age=60, s-maxage=600"], Some((600, Source::SMaxAge))),
+            (&["max-age=bogus, s-maxage=30"], Some((30, Source::SMaxAge))),
+            (&["max-age=-5"], None),
+            (&["max-age=0"], None),
+            (&["no-cache, max-age=300"], None),
+            (&["no-store"], None),
+            (
+                &["private, no-cache=\"Set-Cookie, max-age\", max-age=60"],
+                Some((60, Source::MaxAge)),
+            ),
+            (
+                &["max-age=99999999999999999999"],
+                Some((i64::MAX / 1000, Source::MaxAge)),
+            ),
+            (&["public, must-revalidate"], None),
+            (&[""], None),
+        ];
+        for (values, expected) in cases {
+            let headers = values.iter().map(|v| (CC, *v)).collect::<Vec<_>>();
+            assert_eq!(seconds(policy(&headers)), *expected, "{:?}", values);
+        }
+
+        let no_cache = policy(&[(CC, "no-cache=\"Set-Cookie\"")]);
+        assert!(!no_cache.no_cache);
+        assert!(policy(&[(CC, "No-Cache")]).no_cache);
+    }
+
+    #[test]
+    fn check_expires_and_age_parse() {
+        use header::{AGE, CACHE_CONTROL, DATE, EXPIRES};
+        let date = "Sat, 01 May 2021 00:00:00 GMT";
+
+        assert_eq!(
+            seconds(policy(&[
+                (DATE, date),
+                (EXPIRES, "Sat, 01 May 2021 01:00:00 GMT")
+            ])),
+            Some((3600, Source::Expires))
+        );
+        // Measured from the server's `Date`, not our clock.
+        assert_eq!(
+            seconds(policy(&[
+                (DATE, "Fri, 30 Apr 2021 23:00:00 GMT"),
+                (EXPIRES, "Sat, 01 May 2021 00:00:00 GMT")
+            ])),
+            Some((3600, Source::Expires))
+        );
+        assert_eq!(
+            seconds(policy(&[(EXPIRES, "Saturday, 01-May-21 00:10:00 GMT")])),
+            Some((600, Source::Expires))
+        );
+        assert_eq!(
+            seconds(policy(&[(EXPIRES, "Sat May  1 00:10:00 2021")])),
+            Some((600, Source::Expires))
+        );
+        // Invalid dates (like the common `0` and `-1`) mean it already expired.
+        assert_eq!(policy(&[(EXPIRES, "0")]).expires, Some(0));
+        assert_eq!(seconds(policy(&[(EXPIRES, "-1")])), None);
+
+        // `max-age` wins over `Expires`.
+        assert_eq!(
+            seconds(policy(&[(EXPIRES, "0"), (CACHE_CONTROL, "max-age=120")])),
+            Some((120, Source::MaxAge))
+        );
+
+        // Time spent in other caches counts against the lifetime.
+        assert_eq!(
+            seconds(policy(&[(CACHE_CONTROL, "max-age=300"), (AGE, "100")])),
+            Some((200, Source::MaxAge))
+        );
+        assert_eq!(
+            seconds(policy(&[(CACHE_CONTROL, "max-age=300"), (AGE, "400")])),
+            None
+        );
+        assert_eq!(
+            seconds(policy(&[(CACHE_CONTROL, "max-age=300"), (AGE, "soon")])),
+            Some((300, Source::MaxAge))
+        );
+    }
+}
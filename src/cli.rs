@@ -0,0 +1,345 @@
+use crate::cache::CachePolicy;
+use crate::credentials::Credentials;
+use crate::db::{self, Database};
+use crate::feed::Feed;
+use crate::storage::Storage;
+use crate::target::Target;
+use crate::{string, Result, DB_NAME};
+use tokio::time::Instant;
+
+static USAGE: &str = "Usage: srsrssrsbot [COMMAND]
+
+Without a command, the bot is run. Otherwise, one of:
+    check <URL>               fetch the feed twice without storing anything, and print its headers and
+                              what was found
+    list-feeds                print every feed along with when it's checked and how many subscribers it has
+    list-subscribers <URL>    print who's subscribed to the feed
+    remove-feed <URL>         delete the feed, its entries and its subscribers
+    ban <USER ID> [REASON]    ignore the Telegram user's messages and stop sending them entries
+    unban <USER ID>           lift the ban on the Telegram user
+    list-bans                 print every banned user along with why and when
+    vacuum                    reclaim the space left behind by deleted rows
+    integrity-check [--repair]
+                              report corruption, orphan rows, invalid chats and impossible timestamps,
+                              and with --repair, fix all but corruption (invalid chats are quarantined)
+    migrate                   upgrade the database to the latest version and exit";
+
+/// Run the operator command given in the arguments (without the program name).
+pub async fn run(args: &[String]) -> Result<()> {
+    run_on(DB_NAME, args).await
+}
+
+/// Like `run`, with the database at `name`. Commands that only read it don't upgrade it.
+async fn run_on(name: &str, args: &[String]) -> Result<()> {
+    let command = args.first().map(String::as_str).unwrap_or("");
+    match (command, args.get(1..).unwrap_or(&[])) {
+        ("check", [url]) => check(url).await,
+        ("list-feeds", []) => list_feeds(&Database::open_read_only(name)?).await,
+        ("list-subscribers", [url]) => {
+            list_subscribers(&Database::open_read_only(name)?, url).await
+        }
+        ("remove-feed", [url]) => remove_feed(&Database::new(name)?, url).await,
+        ("ban", [user, reason @ ..]) => ban(&Database::new(name)?, user, reason).await,
+        ("unban", [user]) => unban(&Database::new(name)?, user).await,
+        ("list-bans", []) => list_bans(&Database::open_read_only(name)?).await,
+        ("vacuum", []) => Ok(Database::new(name)?.vacuum().await?),
+        ("integrity-check", []) => integrity_check(&Database::open_read_only(name)?, false).await,
+        ("integrity-check", [flag]) if flag == "--repair" => {
+            integrity_check(&Database::new(name)?, true).await
+        }
+        ("migrate", []) => {
+            Database::new(name)?;
+            println!("{} is at version {}", name, db::VERSION);
+            Ok(())
+        }
+        ("help", []) | ("--help", []) | ("-h", []) => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            Err(format!("invalid command: {}", args.join(" ")).into())
+        }
+    }
+}
+
+fn seconds_until(when: Instant) -> u64 {
+    when.saturating_duration_since(Instant::now()).as_secs()
+}
+
+fn print_feed(feed: &Feed) {
+    println!("Hints: {:?}", feed.hints);
+    println!("Stats: {:?}", feed.stats);
+    match feed.hub.as_ref() {
+        Some(hub) => println!("Hub: {} (topic {})", hub.hub, hub.topic),
+        None => println!("Hub: none"),
+    }
+    println!(
+        "Next check in {}s (scheduled by {})",
+        seconds_until(feed.next_fetch),
+        feed.scheduled_by
+    );
+}
+
+async fn check(url: &str) -> Result<()> {
+    let http = reqwest::Client::new();
+    let (url, credentials) = Credentials::from_url(url).ok_or("malformed credentials in url")?;
+    let url = url.as_str();
+
+    // The response to the first fetch is shown before the feed is made out of it.
+    let mut request = http.get(url);
+    if let Some(credentials) = credentials.as_ref() {
+        request = credentials.apply(request);
+    }
+    let resp = request.send().await?;
+    println!("{} {}", resp.status(), url);
+    for (name, value) in resp.headers() {
+        println!("{}: {}", name, value.to_str().unwrap_or("(not text)"));
+    }
+    let policy = CachePolicy::parse(resp.headers());
+    println!("\nCache policy: {:?}", policy);
+    match policy.freshness() {
+        Some((delay, source)) => println!("Fresh for {}s (from {})", delay.num_seconds(), source),
+        None => println!("Fresh for: unknown"),
+    }
+
+    let resp = resp.error_for_status()?;
+    let mut feed = Feed::from_response(url, credentials, resp).await?;
+    let mut entries = feed.seen_entries.iter().collect::<Vec<_>>();
+    entries.sort_by_key(|(_, fingerprint)| std::cmp::Reverse(fingerprint.updated));
+    println!("\nFound {} entries:", entries.len());
+    for (id, fingerprint) in entries {
+        println!(
+            "• {}\n  {}\n  id {}, updated {}",
+            fingerprint.title.as_deref().unwrap_or("(untitled)"),
+            fingerprint.link.as_deref().unwrap_or("(no online url)"),
+            id,
+            fingerprint
+                .updated
+                .map(string::format_timestamp)
+                .unwrap_or_else(|| "unknown".to_string())
+        );
+    }
+    println!();
+    print_feed(&feed);
+
+    // A second fetch shows whether conditional requests work and what would be notified.
+    let changes = feed.check(&http).await?;
+    println!("\nChecking again found {} changes:", changes.len());
+    for change in changes.iter() {
+        let text = if change.updated {
+            string::updated_entry(&change.entry)
+        } else {
+            string::new_entry(&change.entry)
+        };
+        println!("• {}", text.replace('\n', "\n  "));
+    }
+    println!();
+    print_feed(&feed);
+    Ok(())
+}
+
+async fn list_feeds(db: &dyn Storage) -> Result<()> {
+    for (url, last_check, next_check, scheduled_by, subscribers) in db.feed_summaries().await? {
+        println!(
+            "{}\n  checked {}, next {} (scheduled by {}), {} subscribers",
+            url,
+            string::format_timestamp(last_check),
+            string::format_timestamp(next_check),
+            scheduled_by,
+            subscribers
+        );
+    }
+    Ok(())
+}
+
+async fn list_subscribers(db: &dyn Storage, url: &str) -> Result<()> {
+    for sub in db.get_subscribers(url).await? {
+        let kind = match sub.target {
+            Target::Telegram(_) => "telegram",
+            Target::Webhook { .. } => "webhook",
+            Target::Email { digest: false, .. } => "email",
+            Target::Email { digest: true, .. } => "email digest",
+            Target::Matrix { .. } => "matrix",
+        };
+        println!(
+            "{}\t{}{}",
+            kind,
+            sub.target,
+            if sub.notify_updates {
+                "\t(with edits)"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(())
+}
+
+async fn remove_feed(db: &dyn Storage, url: &str) -> Result<()> {
+    if db.remove_feed(url).await? {
+        println!("Removed {}", url);
+        Ok(())
+    } else {
+        Err(format!("no such feed: {}", url).into())
+    }
+}
+
+async fn ban(db: &dyn Storage, user: &str, reason: &[String]) -> Result<()> {
+    let reason = Some(reason.join(" ")).filter(|reason| !reason.is_empty());
+    if db.ban(user.parse()?, reason.as_deref()).await? {
+        println!("Banned {}", user);
+        Ok(())
+    } else {
+        Err(format!("already banned: {}", user).into())
+    }
+}
+
+async fn unban(db: &dyn Storage, user: &str) -> Result<()> {
+    if db.unban(user.parse()?).await? {
+        println!("Unbanned {}", user);
+        Ok(())
+    } else {
+        Err(format!("not banned: {}", user).into())
+    }
+}
+
+async fn list_bans(db: &dyn Storage) -> Result<()> {
+    for (user, reason, banned_at) in db.get_bans().await? {
+        println!(
+            "{}	{}	{}",
+            user,
+            string::format_timestamp(banned_at),
+            reason.as_deref().unwrap_or("(no reason)")
+        );
+    }
+    Ok(())
+}
+
+async fn integrity_check(db: &Database, repair: bool) -> Result<()> {
+    let integrity = db.check_integrity(repair).await?;
+    let sections = [
+        ("Corruption", &integrity.corruption),
+        ("Orphan rows", &integrity.orphans),
+        ("Invalid chats", &integrity.invalid_chats),
+        ("Impossible timestamps", &integrity.impossible_timestamps),
+    ];
+    for (title, problems) in sections.iter().filter(|(_, problems)| !problems.is_empty()) {
+        println!("{}:", title);
+        for problem in problems.iter() {
+            println!("  {}", problem);
+        }
+    }
+
+    if integrity.is_ok() {
+        println!("No problems found");
+        Ok(())
+    } else if !integrity.corruption.is_empty() {
+        Err("the database file is corrupted, restore it from a backup".into())
+    } else if repair {
+        println!("Repaired");
+        Ok(())
+    } else {
+        Err("found problems, run `integrity-check --repair` to fix them".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[tokio::test]
+    async fn check_fetches_twice() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let make_svc = make_service_fn({
+            let requests = Arc::clone(&requests);
+            move |_| {
+                let requests = Arc::clone(&requests);
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_| {
+                        requests.fetch_add(1, Ordering::SeqCst);
+                        async {
+                            Ok::<_, Infallible>(Response::new(Body::from(
+                                r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom"><title>Test</title><id>urn:test</id>
+<entry><title>Entry</title><id>1</id><link href="https://example.com/1"/></entry></feed>"#,
+                            )))
+                        }
+                    }))
+                }
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let port = server.local_addr().port();
+        tokio::spawn(server);
+
+        let line = format!("check http://127.0.0.1:{}/atom.xml", port);
+        run_on(":memory:", &args(&line)).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn check_read_only_commands_leave_database_alone() {
+        let path = std::env::temp_dir().join(format!("srsrssrsbot-{}-cli.db", std::process::id()));
+        let name = path.to_str().unwrap().to_string();
+        let read_only = [
+            "list-feeds",
+            "list-subscribers https://example.com/atom.xml",
+            "list-bans",
+            "integrity-check",
+        ];
+
+        // Nothing is created where there was no database.
+        for line in read_only.iter() {
+            assert!(run_on(&name, &args(line)).await.is_err());
+        }
+        assert!(!path.exists());
+
+        // Nor is an old one upgraded.
+        sqlite::open(&name)
+            .unwrap()
+            .execute("CREATE TABLE version (version INTEGER); INSERT INTO version VALUES (1)")
+            .unwrap();
+        for line in read_only.iter() {
+            assert!(run_on(&name, &args(line)).await.is_err());
+        }
+        let conn = sqlite::open(&name).unwrap();
+        let mut stmt = conn.prepare("SELECT version FROM version").unwrap();
+        stmt.next().unwrap();
+        assert_eq!(stmt.read::<i64>(0).unwrap(), 1);
+        drop(stmt);
+        drop(conn);
+        std::fs::remove_file(&path).unwrap();
+
+        // Once migrated, they can read it, and see what the other commands changed.
+        run_on(&name, &args("migrate")).await.unwrap();
+        run_on(&name, &args("ban 123 spam")).await.unwrap();
+        for line in read_only.iter() {
+            run_on(&name, &args(line)).await.unwrap();
+        }
+        let bans = Database::open_read_only(&name)
+            .unwrap()
+            .get_bans()
+            .await
+            .unwrap();
+        assert_eq!(bans.len(), 1);
+        assert!(Database::open_read_only(&name)
+            .unwrap()
+            .ban(456, None)
+            .await
+            .is_err());
+
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = std::fs::remove_file(format!("{}{}", name, suffix));
+        }
+    }
+}
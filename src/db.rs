@@ -1,16 +1,41 @@
-use crate::feed::Feed;
+use crate::credentials::{Credentials, Vault};
+use crate::email::{Digest, Item};
+use crate::feed::{self, Feed, Fingerprint, ScheduleSource, Stats, Subscriber};
+use crate::hints::Hints;
+use crate::metrics::metrics;
+use crate::request::RequestOptions;
+use crate::storage::{Error, FeedInfo, Result, Storage, Totals, PRUNED_ID_RETENTION};
+use crate::string;
+use crate::target::Target;
+use crate::websub::{self, Hub, Subscription};
+use async_trait::async_trait;
 use chrono::{TimeZone, Utc};
-use grammers_client::types::chat::PackedChat;
 use sqlite::State;
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::time::Instant;
+use tokio::task;
+use tracing::warn;
 
-const VERSION: i64 = 1;
+pub const VERSION: i64 = 18;
 
+/// Checks are never scheduled further ahead than this, even when skipping hours and days.
+const MAX_SCHEDULE_AHEAD: i64 = 30 * 24 * 60 * 60;
+
+/// How far ahead a time in the past may be, for clocks which were off when it was saved.
+const MAX_CLOCK_SKEW: i64 = 24 * 60 * 60;
+
+/// Storage in a SQLite database: the connections to it, and the vault to encrypt feed credentials
+/// with if a key was configured.
+///
+/// Queries run on tokio's blocking threads. Writes go through a single connection, while reads
+/// use a separate one, which in WAL mode doesn't wait for the writes to finish. An in-memory
+/// database can't be opened twice, so it uses the same connection for both.
 #[derive(Clone)]
-pub struct Database(Arc<Mutex<sqlite::Connection>>);
+pub struct Database {
+    writer: Arc<Mutex<sqlite::Connection>>,
+    reader: Arc<Mutex<sqlite::Connection>>,
+    vault: Option<Arc<Vault>>,
+}
 
 /// Helper macro to avoid the annoying `prepare` statements and `bind`.
 ///
@@ -96,193 +121,1695 @@ macro_rules! query {
 }
 
 impl Database {
-    pub fn new(name: &str) -> sqlite::Result<Self> {
-        let conn = sqlite::open(name)?;
-        query!(conn."PRAGMA foreign_keys = ON");
+    /// Open the database, upgrading it to the latest version if needed.
+    ///
+    /// This blocks, so it should be done before anything else runs.
+    pub fn new(name: &str) -> Result<Self> {
+        let conn = open(name)?;
+        let version = read_version(&conn)?;
 
-        let version = match conn.prepare("SELECT version FROM version") {
-            Ok(mut stmt) => {
-                assert_eq!(State::Row, stmt.next()?);
-                stmt.read(0)?
-            }
-            Err(err) => {
-                if err
-                    .message
-                    .as_ref()
-                    .filter(|m| m.starts_with("no such table"))
-                    .is_some()
-                {
-                    0
-                } else {
-                    return Err(err);
+        if version > VERSION {
+            return Err(Error::TooNewError(version));
+        }
+
+        if version == VERSION {
+            return Self::with_reader(name, conn);
+        }
+
+        // Rebuilding a table means dropping it, which would otherwise cascade to everything else.
+        query!(conn."PRAGMA foreign_keys = OFF");
+        query!(conn."BEGIN");
+        if version < 1 {
+            query!(conn.
+                "CREATE TABLE version (
+                version INTEGER NOT NULL)"
+            );
+            query!(conn."INSERT INTO version (version) VALUES (0)");
+            query!(conn.
+                "CREATE TABLE feed (
+                id INTEGER PRIMARY KEY,
+                url TEXT NOT NULL UNIQUE ON CONFLICT REPLACE,
+                last_check INTEGER NOT NULL,
+                next_check INTEGER NOT NULL,
+                etag TEXT)"
+            );
+            query!(conn.
+                "CREATE TABLE entry (
+                feed_id INTEGER NOT NULL REFERENCES feed (id) ON DELETE CASCADE,
+                entry_id TEXT NOT NULL,
+                CONSTRAINT non_dup_entries_con UNIQUE (feed_id, entry_id) ON CONFLICT IGNORE)"
+            );
+            query!(conn.
+                "CREATE TABLE subscriber (
+                feed_id INTEGER NOT NULL REFERENCES feed (id) ON DELETE CASCADE,
+                user NOT NULL,
+                CONSTRAINT one_sub_per_feed_con UNIQUE (feed_id, user) ON CONFLICT IGNORE)"
+            );
+        }
+        if version < 2 {
+            // Entry fingerprints, to detect edits. Existing rows are left as `NULL`.
+            query!(conn."ALTER TABLE entry ADD COLUMN title TEXT");
+            query!(conn."ALTER TABLE entry ADD COLUMN link TEXT");
+            query!(conn."ALTER TABLE entry ADD COLUMN updated INTEGER");
+            query!(conn."ALTER TABLE entry ADD COLUMN hash TEXT");
+            query!(conn."ALTER TABLE subscriber ADD COLUMN notify_updates INTEGER NOT NULL DEFAULT 0");
+        }
+        if version < 3 {
+            // Entry timestamps, so that old entries can be pruned.
+            let now = Utc::now().timestamp();
+            query!(conn."ALTER TABLE entry ADD COLUMN first_seen INTEGER NOT NULL DEFAULT 0");
+            query!(conn."ALTER TABLE entry ADD COLUMN last_seen INTEGER NOT NULL DEFAULT 0");
+            query!(conn."UPDATE entry SET first_seen = ?, last_seen = ?"(now, now));
+            query!(conn."ALTER TABLE feed ADD COLUMN pruned_until INTEGER NOT NULL DEFAULT 0");
+        }
+        if version < 4 {
+            // Posting history and the feed's own hints, to adapt the polling interval.
+            query!(conn."ALTER TABLE feed ADD COLUMN polls INTEGER NOT NULL DEFAULT 0");
+            query!(conn."ALTER TABLE feed ADD COLUMN not_modified INTEGER NOT NULL DEFAULT 0");
+            query!(conn."ALTER TABLE feed ADD COLUMN entry_interval INTEGER");
+            query!(conn."ALTER TABLE feed ADD COLUMN last_entry INTEGER");
+            query!(conn."ALTER TABLE feed ADD COLUMN ttl INTEGER");
+            query!(conn."ALTER TABLE feed ADD COLUMN update_period INTEGER");
+            query!(conn."ALTER TABLE feed ADD COLUMN skip_hours INTEGER NOT NULL DEFAULT 0");
+            query!(conn."ALTER TABLE feed ADD COLUMN skip_days INTEGER NOT NULL DEFAULT 0");
+        }
+        if version < 5 {
+            // WebSub subscriptions. A `lease_until` in the past means it's not (yet) active.
+            query!(conn.
+                "CREATE TABLE websub (
+                feed_id INTEGER PRIMARY KEY REFERENCES feed (id) ON DELETE CASCADE,
+                hub TEXT NOT NULL,
+                topic TEXT NOT NULL,
+                secret TEXT NOT NULL,
+                lease_until INTEGER NOT NULL DEFAULT 0,
+                requested INTEGER NOT NULL DEFAULT 0)"
+            );
+        }
+        if version < 6 {
+            // Subscribers other than Telegram chats. The `user` column holds the target's data.
+            query!(conn."ALTER TABLE subscriber ADD COLUMN kind INTEGER NOT NULL DEFAULT 0");
+        }
+        if version < 7 {
+            // Entries waiting to be emailed in a digest. Not tied to the feed, which may go away.
+            query!(conn.
+                "CREATE TABLE digest (
+                id INTEGER PRIMARY KEY,
+                address TEXT NOT NULL,
+                feed_url TEXT NOT NULL,
+                title TEXT NOT NULL,
+                link TEXT NOT NULL,
+                edited INTEGER NOT NULL)"
+            );
+        }
+        if version < 8 {
+            // Deliveries made for entries which are not saved as seen yet, so that they're not
+            // repeated if saving fails or the bot stops before it's done. Keyed by the entry's hash,
+            // because an edit has to be delivered again.
+            query!(conn.
+                "CREATE TABLE delivery (
+                feed_id INTEGER NOT NULL REFERENCES feed (id) ON DELETE CASCADE,
+                entry_id TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                kind INTEGER NOT NULL,
+                user BLOB NOT NULL,
+                UNIQUE (feed_id, entry_id, hash, kind, user) ON CONFLICT IGNORE)"
+            );
+        }
+        if version < 9 {
+            // The server's `Last-Modified`, verbatim. Rows from before this are sent no date at all.
+            query!(conn."ALTER TABLE feed ADD COLUMN last_modified TEXT");
+        }
+        if version < 10 {
+            // What decided `next_check`, as `ScheduleSource::as_str`.
+            query!(conn."ALTER TABLE feed ADD COLUMN scheduled_by TEXT NOT NULL DEFAULT 'default'");
+        }
+        if version < 11 {
+            // Polling overrides set with `/interval` and `/ignorecache`. `NULL` means not set.
+            query!(conn."ALTER TABLE feed ADD COLUMN interval INTEGER");
+            query!(conn."ALTER TABLE feed ADD COLUMN ignore_cache INTEGER");
+        }
+        if version < 12 {
+            // Credentials, sealed by the vault, and their fingerprint (empty without any). The same
+            // URL can now be several feeds, one per set of credentials.
+            query!(conn.
+                "CREATE TABLE feed_new (
+                id INTEGER PRIMARY KEY,
+                url TEXT NOT NULL,
+                credentials BLOB,
+                credentials_id TEXT NOT NULL DEFAULT '',
+                last_check INTEGER NOT NULL,
+                next_check INTEGER NOT NULL,
+                etag TEXT,
+                pruned_until INTEGER NOT NULL DEFAULT 0,
+                polls INTEGER NOT NULL DEFAULT 0,
+                not_modified INTEGER NOT NULL DEFAULT 0,
+                entry_interval INTEGER,
+                last_entry INTEGER,
+                ttl INTEGER,
+                update_period INTEGER,
+                skip_hours INTEGER NOT NULL DEFAULT 0,
+                skip_days INTEGER NOT NULL DEFAULT 0,
+                last_modified TEXT,
+                scheduled_by TEXT NOT NULL DEFAULT 'default',
+                interval INTEGER,
+                ignore_cache INTEGER,
+                CONSTRAINT one_feed_per_credentials_con UNIQUE (url, credentials_id) ON CONFLICT REPLACE)"
+            );
+            query!(conn."INSERT INTO feed_new (id, url, last_check, next_check, etag, pruned_until,
+                polls, not_modified, entry_interval, last_entry, ttl, update_period, skip_hours, skip_days,
+                last_modified, scheduled_by, interval, ignore_cache)
+                SELECT id, url, last_check, next_check, etag, pruned_until,
+                polls, not_modified, entry_interval, last_entry, ttl, update_period, skip_hours, skip_days,
+                last_modified, scheduled_by, interval, ignore_cache FROM feed");
+            query!(conn."DROP TABLE feed");
+            query!(conn."ALTER TABLE feed_new RENAME TO feed");
+        }
+        if version < 13 {
+            // Request options. The headers are stored one `Name: value` per line.
+            query!(conn."ALTER TABLE feed ADD COLUMN proxy TEXT");
+            query!(conn."ALTER TABLE feed ADD COLUMN headers TEXT");
+            query!(conn."ALTER TABLE feed ADD COLUMN ca_bundle TEXT");
+        }
+        if version < 14 {
+            // Telegram users whose messages are ignored, and who no longer get any entries.
+            query!(conn."CREATE TABLE ban (
+                user INTEGER PRIMARY KEY,
+                reason TEXT,
+                banned_at INTEGER NOT NULL)"
+            );
+        }
+        if version < 15 {
+            // How many checks in a row failed, and why the last one did, for the admins to see.
+            query!(conn."ALTER TABLE feed ADD COLUMN failures INTEGER NOT NULL DEFAULT 0");
+            query!(conn."ALTER TABLE feed ADD COLUMN last_error TEXT");
+            // Deliveries per day (since the epoch, in UTC). The markers are deleted as they're saved.
+            query!(conn."CREATE TABLE daily_deliveries (
+                day INTEGER PRIMARY KEY,
+                count INTEGER NOT NULL)"
+            );
+        }
+        if version < 16 {
+            // Rows which couldn't be decoded, moved aside so that they don't break anything. `data`
+            // holds the row as JSON, to be fixed and put back by hand.
+            query!(conn."CREATE TABLE quarantine (
+                id INTEGER PRIMARY KEY,
+                source TEXT NOT NULL,
+                data TEXT NOT NULL,
+                reason TEXT NOT NULL,
+                quarantined_at INTEGER NOT NULL)"
+            );
+        }
+        if version < 17 {
+            // Who a subscriber belongs to, and the webhook secret, which used to follow the URL in
+            // `user`. A subscriber is now the same one if it's for the same target and owner.
+            query!(conn.
+                "CREATE TABLE subscriber_new (
+                feed_id INTEGER NOT NULL REFERENCES feed (id) ON DELETE CASCADE,
+                user NOT NULL,
+                notify_updates INTEGER NOT NULL DEFAULT 0,
+                kind INTEGER NOT NULL DEFAULT 0,
+                owner INTEGER NOT NULL DEFAULT 0,
+                secret TEXT,
+                CONSTRAINT one_sub_per_feed_con UNIQUE (feed_id, kind, user, owner) ON CONFLICT IGNORE)"
+            );
+            query!(conn."INSERT INTO subscriber_new (rowid, feed_id, user, notify_updates, kind)
+                SELECT rowid, feed_id, user, notify_updates, kind FROM subscriber");
+            query!(conn."DROP TABLE subscriber");
+            query!(conn."ALTER TABLE subscriber_new RENAME TO subscriber");
+
+            let mut rows = Vec::new();
+            query!(for (rowid: i64, kind: i64, user: Vec<u8>) in conn."SELECT rowid, kind, user FROM subscriber"() {
+                rows.push((rowid, kind, user));
+            });
+            for (rowid, kind, user) in rows {
+                if kind == 0 {
+                    if let Some(target) = Target::from_row(kind, &user, 0, None) {
+                        query!(conn."UPDATE subscriber SET owner = ? WHERE rowid = ?"(target.owner(), rowid));
+                    }
+                } else if kind == 1 {
+                    // Who added older hooks is unknown, so they are nobody's and only go with their feed.
+                    if let Some(newline) = user.iter().position(|&b| b == b'\n') {
+                        let secret = String::from_utf8_lossy(&user[newline + 1..]).into_owned();
+                        query!(conn."UPDATE OR REPLACE subscriber SET user = ?, secret = ? WHERE rowid = ?"(
+                            &user[..newline], secret.as_str(), rowid
+                        ));
+                    }
                 }
             }
+        }
+        if version < 18 {
+            // The IDs of pruned entries, to recognize them if they reappear, whether they have a
+            // date or not. `pruned_at` is our own clock, like `first_seen` and `last_seen`.
+            query!(conn."CREATE TABLE pruned_entry (
+                feed_id INTEGER NOT NULL REFERENCES feed (id) ON DELETE CASCADE,
+                entry_id TEXT NOT NULL,
+                pruned_at INTEGER NOT NULL,
+                PRIMARY KEY (feed_id, entry_id))"
+            );
+        }
+        query!(conn."UPDATE version SET version = ?"(VERSION));
+        query!(conn."COMMIT");
+        query!(conn."PRAGMA foreign_keys = ON");
+        Self::with_reader(name, conn)
+    }
+
+    /// Open the database without writing anything to it, not even to upgrade it, for the commands
+    /// which only look into it. It has to exist and be at the latest version already.
+    pub fn open_read_only(name: &str) -> Result<Self> {
+        let conn =
+            sqlite::Connection::open_with_flags(name, sqlite::OpenFlags::new().set_read_only())?;
+        query!(conn."PRAGMA busy_timeout = 5000");
+        let version = read_version(&conn)?;
+        if version > VERSION {
+            return Err(Error::TooNewError(version));
+        }
+        if version < VERSION {
+            return Err(Error::TooOldError(version));
+        }
+
+        let conn = Arc::new(Mutex::new(conn));
+        Ok(Self {
+            writer: Arc::clone(&conn),
+            reader: conn,
+            vault: None,
+        })
+    }
+
+    fn with_reader(name: &str, writer: sqlite::Connection) -> Result<Self> {
+        let writer = Arc::new(Mutex::new(writer));
+        let reader = if name == ":memory:" {
+            Arc::clone(&writer)
+        } else {
+            let conn = open(name)?;
+            query!(conn."PRAGMA query_only = ON");
+            Arc::new(Mutex::new(conn))
         };
+        Ok(Self {
+            writer,
+            reader,
+            vault: None,
+        })
+    }
 
-        assert!(
-            version <= VERSION,
-            "tried to load a database which is too new"
-        );
+    /// Encrypt feed credentials with the vault. Without one, feeds can't have credentials.
+    pub fn with_vault(self, vault: Vault) -> Self {
+        Self {
+            vault: Some(Arc::new(vault)),
+            ..self
+        }
+    }
 
-        if version == VERSION {
-            return Ok(Self(Arc::new(Mutex::new(conn))));
+    /// Run the queries with the connection for writes, on a blocking thread so that the runtime
+    /// can keep going meanwhile. Writes wait for each other here, and for other processes in SQLite.
+    async fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&sqlite::Connection) -> sqlite::Result<T> + Send + 'static,
+    {
+        run(Arc::clone(&self.writer), f).await
+    }
+
+    /// Like `write`, but with the connection for reads, which doesn't wait for the writes.
+    async fn read<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&sqlite::Connection) -> sqlite::Result<T> + Send + 'static,
+    {
+        run(Arc::clone(&self.reader), f).await
+    }
+
+    /// The fingerprint that tells the feed's credentials apart, or an empty string without any.
+    fn credentials_id(&self, credentials: Option<&Credentials>) -> Result<String> {
+        match (credentials, self.vault.as_ref()) {
+            (None, _) => Ok(String::new()),
+            (Some(credentials), Some(vault)) => Ok(vault.fingerprint(credentials)),
+            (Some(_), None) => Err(Error::NoVaultError),
         }
+    }
 
-        query!(conn."BEGIN");
-        query!(conn.
-            "CREATE TABLE version (
-            version INTEGER NOT NULL)"
-        );
-        query!(conn."INSERT INTO version (version) VALUES (?)"(VERSION));
-        query!(conn.
-            "CREATE TABLE feed (
-            id INTEGER PRIMARY KEY,
-            url TEXT NOT NULL UNIQUE ON CONFLICT REPLACE,
-            last_check INTEGER NOT NULL,
-            next_check INTEGER NOT NULL,
-            etag TEXT)"
-        );
-        query!(conn.
-            "CREATE TABLE entry (
-            feed_id INTEGER NOT NULL REFERENCES feed (id) ON DELETE CASCADE,
-            entry_id TEXT NOT NULL,
-            CONSTRAINT non_dup_entries_con UNIQUE (feed_id, entry_id) ON CONFLICT IGNORE)"
-        );
-        query!(conn.
-            "CREATE TABLE subscriber (
-            feed_id INTEGER NOT NULL REFERENCES feed (id) ON DELETE CASCADE,
-            user NOT NULL,
-            CONSTRAINT one_sub_per_feed_con UNIQUE (feed_id, user) ON CONFLICT IGNORE)"
-        );
-        query!(conn."COMMIT");
-        Ok(Self(Arc::new(Mutex::new(conn))))
+    /// Rebuild the database file, reclaiming the space left behind by deleted rows.
+    pub async fn vacuum(&self) -> Result<()> {
+        self.write(|conn| {
+            query!(conn."VACUUM");
+            Ok(())
+        })
+        .await
     }
 
-    pub fn add_feed(&self, feed: &Feed) -> sqlite::Result<()> {
-        let conn = self.0.lock().unwrap();
-        query!(conn."BEGIN");
-        {
-            query!(conn."INSERT INTO feed (url, last_check, next_check, etag) VALUES (?, ?, ?, ?)"(
-                feed.url.as_str(), feed.last_fetch.timestamp(), feed.next_fetch_timestamp(), feed.etag.as_deref()
-            ));
-            let feed_id = query!(fetch (id: i64) in conn."SELECT last_insert_rowid()"()).unwrap();
+    /// Look for what would make the bot misbehave: corruption of the file itself, rows left
+    /// behind by a deleted feed, subscribers whose chat can't be decoded and times which can't be
+    /// right. With `repair`, all but corruption is fixed, and the invalid chats are quarantined.
+    pub async fn check_integrity(&self, repair: bool) -> Result<Integrity> {
+        self.write(move |conn| {
+            if !repair {
+                return check_integrity(conn, false);
+            }
+            query!(conn."BEGIN");
+            match check_integrity(conn, true) {
+                Ok(integrity) => {
+                    query!(conn."COMMIT");
+                    Ok(integrity)
+                }
+                Err(e) => {
+                    let _ = conn.execute("ROLLBACK");
+                    Err(e)
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// What `Database::check_integrity` found, one line per problem.
+#[derive(Debug, Default)]
+pub struct Integrity {
+    /// What SQLite found wrong with the file. It can't be repaired here.
+    pub corruption: Vec<String>,
+    /// Entries, subscribers, deliveries and WebSub subscriptions whose feed doesn't exist.
+    pub orphans: Vec<String>,
+    /// Subscribers whose chat (or other target) can't be decoded.
+    pub invalid_chats: Vec<String>,
+    /// Times out of range, or in the future when they record something that happened.
+    pub impossible_timestamps: Vec<String>,
+}
+
+impl Integrity {
+    pub fn is_ok(&self) -> bool {
+        self.corruption.is_empty()
+            && self.orphans.is_empty()
+            && self.invalid_chats.is_empty()
+            && self.impossible_timestamps.is_empty()
+    }
+}
+
+#[async_trait]
+impl Storage for Database {
+    fn can_store_credentials(&self) -> bool {
+        self.vault.is_some()
+    }
 
-            for entry_id in feed.seen_entries.iter() {
-                query!(conn."INSERT INTO entry (feed_id, entry_id) VALUES (?, ?)"(feed_id, entry_id.as_str()));
+    /// Store a new feed along with its subscribers, returning the ID it was given.
+    async fn add_feed(&self, feed: &Feed) -> Result<i64> {
+        let credentials_id = self.credentials_id(feed.credentials.as_ref())?;
+        let sealed = feed
+            .credentials
+            .as_ref()
+            .zip(self.vault.as_ref())
+            .map(|(credentials, vault)| vault.seal(&feed.url, credentials));
+        let feed = SavedFeed::of(feed);
+        self.write(move |conn| {
+            let _timer = metrics().transaction("add_feed");
+            query!(conn."BEGIN");
+            match insert_feed(conn, &feed, sealed.as_deref(), &credentials_id) {
+                Ok(feed_id) => {
+                    query!(conn."COMMIT");
+                    Ok(feed_id)
+                }
+                Err(e) => {
+                    let _ = conn.execute("ROLLBACK");
+                    Err(e)
+                }
             }
-            for sub in feed.users.iter() {
-                query!(conn."INSERT INTO subscriber (feed_id, user) VALUES (?, ?)"(
-                    feed_id, sub.to_bytes().as_slice()
-                ));
+        })
+        .await
+    }
+
+    /// Save the state of a feed that was just checked, along with the entries it has seen.
+    ///
+    /// Everything is saved or nothing is, and a feed which no longer exists is ignored.
+    async fn update_feed(&self, feed: &Feed) -> Result<()> {
+        let feed = SavedFeed::of(feed);
+        self.write(move |conn| {
+            let _timer = metrics().transaction("update_feed");
+            query!(conn."BEGIN");
+            match save_feed(conn, &feed) {
+                Ok(()) => {
+                    query!(conn."COMMIT");
+                    Ok(())
+                }
+                Err(e) => {
+                    // Otherwise the transaction would stay open and every later `BEGIN` would fail.
+                    let _ = conn.execute("ROLLBACK");
+                    Err(e)
+                }
             }
-        }
-        query!(conn."COMMIT");
-        Ok(())
+        })
+        .await
     }
 
-    pub fn update_feeds_and_entries(&self, feeds: &[Feed]) -> sqlite::Result<()> {
-        let conn = self.0.lock().unwrap();
-        query!(conn."BEGIN");
-        for feed in feeds {
-            let feed_id = match query!(fetch (id: i64) in conn."SELECT id FROM feed WHERE url = ?"(feed.url.as_str()))
-            {
-                Some(id) => id,
-                None => continue,
-            };
-            query!(conn."UPDATE feed SET last_check = ?, next_check = ?, etag = ? WHERE id = ?"(
-                feed.last_fetch.timestamp(), feed.next_fetch_timestamp(), feed.etag.as_deref(), feed_id
+    /// Whether this version of the entry was already delivered to the target.
+    async fn was_delivered(
+        &self,
+        feed_id: i64,
+        entry_id: &str,
+        hash: &str,
+        target: &Target,
+    ) -> Result<bool> {
+        let (entry_id, hash, target) = (entry_id.to_string(), hash.to_string(), target.clone());
+        self.read(move |conn| {
+            Ok(
+                query!(fetch (count: i64) in conn."SELECT COUNT(*) FROM delivery
+                    WHERE feed_id = ? AND entry_id = ? AND hash = ? AND kind = ? AND user = ?"(
+                    feed_id, entry_id.as_str(), hash.as_str(), target.kind(), target.data().as_slice()
+                ))
+                .unwrap_or(0)
+                    != 0,
+            )
+        })
+        .await
+    }
+
+    /// Remember that this version of the entry was delivered to the target, until the entry is saved.
+    async fn mark_delivered(
+        &self,
+        feed_id: i64,
+        entry_id: &str,
+        hash: &str,
+        target: &Target,
+    ) -> Result<()> {
+        let (entry_id, hash, target) = (entry_id.to_string(), hash.to_string(), target.clone());
+        self.write(move |conn| {
+            query!(conn."INSERT INTO delivery (feed_id, entry_id, hash, kind, user) VALUES (?, ?, ?, ?, ?)"(
+                feed_id, entry_id.as_str(), hash.as_str(), target.kind(), target.data().as_slice()
             ));
-            for entry_id in feed.seen_entries.iter() {
-                query!(conn."INSERT INTO entry (feed_id, entry_id) VALUES (?, ?)"(feed_id, entry_id.as_str()));
+            let day = Utc::now().timestamp() / (24 * 60 * 60);
+            query!(conn."INSERT OR IGNORE INTO daily_deliveries (day, count) VALUES (?, 0)"(day));
+            query!(conn."UPDATE daily_deliveries SET count = count + 1 WHERE day = ?"(day));
+            Ok(())
+        })
+        .await
+    }
+
+    /// Record whether checking the feed worked, keeping count of how many times in a row it didn't.
+    async fn record_check(&self, feed_id: i64, error: Option<&str>) -> Result<()> {
+        let error = error.map(String::from);
+        self.write(move |conn| {
+            match error {
+                Some(error) => {
+                    query!(conn."UPDATE feed SET failures = failures + 1, last_error = ? WHERE id = ?"(error.as_str(), feed_id))
+                }
+                None => {
+                    query!(conn."UPDATE feed SET failures = 0, last_error = NULL WHERE id = ?"(feed_id))
+                }
             }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Totals for the admins: users, feeds, entries, deliveries today and feeds failing to be checked.
+    async fn totals(&self) -> Result<Totals> {
+        self.read(|conn| {
+            let count = |query: &str| -> sqlite::Result<i64> {
+                let mut stmt = conn.prepare(query)?;
+                stmt.next()?;
+                stmt.read::<i64>(0)
+            };
+            let day = Utc::now().timestamp() / (24 * 60 * 60);
+            Ok(Totals {
+                users: count("SELECT COUNT(*) FROM (SELECT DISTINCT kind, user FROM subscriber)")?,
+                feeds: count("SELECT COUNT(*) FROM feed")?,
+                entries: count("SELECT COUNT(*) FROM entry")?,
+                deliveries_today: query!(fetch (count: i64) in conn."SELECT count FROM daily_deliveries WHERE day = ?"(day))
+                    .unwrap_or(0),
+                failing_feeds: count("SELECT COUNT(*) FROM feed WHERE failures > 0")?,
+            })
+        })
+        .await
+    }
+
+    /// What's known about each of the feeds with the URL, one per set of credentials.
+    async fn feed_info(&self, url: &str) -> Result<Vec<FeedInfo>> {
+        let url = url.to_string();
+        self.read(move |conn| {
+            let mut result = Vec::new();
+            query!(for (id: i64, has_credentials: i64, last_check: i64, next_check: i64, scheduled_by: String,
+                        etag: Option<String>, last_modified: Option<String>, failures: i64, last_error: Option<String>, subscribers: i64)
+                    in conn."SELECT id, credentials_id != '', last_check, next_check, scheduled_by, etag, last_modified,
+                        failures, last_error, (SELECT COUNT(*) FROM subscriber AS s WHERE s.feed_id = f.id)
+                        FROM feed AS f WHERE url = ? ORDER BY id"(url.as_str()) {
+                result.push(FeedInfo {
+                    id,
+                    has_credentials: has_credentials != 0,
+                    subscribers,
+                    last_check,
+                    next_check,
+                    scheduled_by,
+                    etag,
+                    last_modified,
+                    failures,
+                    last_error,
+                });
+            });
+            Ok(result)
+        })
+        .await
+    }
+
+    /// Everyone subscribed through Telegram to at least one feed.
+    async fn get_chat_subscribers(&self) -> Result<Vec<Target>> {
+        self.read(|conn| {
+            let mut result = Vec::new();
+            query!(for (kind: i64, user: Vec<u8>)
+                    in conn."SELECT DISTINCT kind, user FROM subscriber"() {
+                if let Some(target @ Target::Telegram(_)) = Target::from_row(kind, &user, 0, None) {
+                    result.push(target);
+                }
+            });
+            Ok(result)
+        })
+        .await
+    }
+
+    /// Make sure the database can still be written to, without changing anything.
+    async fn check_writable(&self) -> Result<()> {
+        self.write(|conn| {
+            query!(conn."BEGIN IMMEDIATE");
+            let result = conn.execute("UPDATE version SET version = version");
+            query!(conn."ROLLBACK");
+            result
+        })
+        .await
+    }
+
+    async fn cleanup_feeds(&self) -> Result<()> {
+        self.write(|conn| {
+            query!(conn."DELETE FROM feed AS f WHERE NOT EXISTS (
+                SELECT * FROM subscriber AS s WHERE s.feed_id = f.id)");
+            Ok(())
+        })
+        .await
+    }
+
+    /// Delete the feeds with the URL along with their subscribers and entries, whichever
+    /// credentials they use. Returns `false` if there were none.
+    async fn remove_feed(&self, url: &str) -> Result<bool> {
+        let url = url.to_string();
+        self.write(move |conn| {
+            query!(conn."DELETE FROM feed WHERE url = ?"(url.as_str()));
+            Ok(query!(fetch (count: i64) in conn."SELECT changes()"()).unwrap_or(0) != 0)
+        })
+        .await
+    }
+
+    /// Every feed as `(url, last_check, next_check, scheduled_by, subscriber count)`.
+    async fn feed_summaries(&self) -> Result<Vec<(String, i64, i64, String, i64)>> {
+        self.read(|conn| {
+            let mut result = Vec::new();
+            query!(for (url: String, last_check: i64, next_check: i64, scheduled_by: String, subscribers: i64)
+                    in conn."SELECT url, last_check, next_check, scheduled_by, (
+                        SELECT COUNT(*) FROM subscriber WHERE feed_id = feed.id
+                    ) FROM feed ORDER BY url"() {
+                result.push((url, last_check, next_check, scheduled_by, subscribers));
+            });
+            Ok(result)
+        })
+        .await
+    }
+
+    /// Delete entries which are no longer in their feed and were last seen before `until`.
+    ///
+    /// An entry is considered to no longer be in the feed if it was not present in the most recent
+    /// fetch (the one with the greatest `last_seen` for that feed). Their IDs are kept aside for
+    /// `PRUNED_ID_RETENTION`, so that they're not notified again if they reappear.
+    async fn prune_entries(&self, until: i64) -> Result<usize> {
+        self.write(move |conn| {
+            let _timer = metrics().transaction("prune_entries");
+            let now = Utc::now().timestamp();
+            query!(conn."BEGIN");
+            query!(conn."UPDATE feed SET pruned_until = MAX(pruned_until, COALESCE((
+                SELECT MAX(e.first_seen) FROM entry AS e
+                WHERE e.feed_id = feed.id AND e.last_seen < ? AND e.last_seen < (
+                    SELECT MAX(last_seen) FROM entry WHERE feed_id = e.feed_id)
+            ), 0))"(until));
+            query!(conn."INSERT OR REPLACE INTO pruned_entry (feed_id, entry_id, pruned_at)
+                SELECT e.feed_id, e.entry_id, ? FROM entry AS e WHERE e.last_seen < ? AND e.last_seen < (
+                    SELECT MAX(last_seen) FROM entry WHERE feed_id = e.feed_id)"(now, until));
+            query!(conn."DELETE FROM entry AS e WHERE e.last_seen < ? AND e.last_seen < (
+                SELECT MAX(last_seen) FROM entry WHERE feed_id = e.feed_id)"(until));
+            let count = query!(fetch (count: i64) in conn."SELECT changes()"()).unwrap_or(0);
+            query!(conn."DELETE FROM pruned_entry WHERE pruned_at < ?"(now - PRUNED_ID_RETENTION));
+            query!(conn."COMMIT");
+            Ok(count as usize)
+        })
+        .await
+    }
+
+    async fn pruned_entries(&self, feed_id: i64, entry_ids: &[String]) -> Result<HashSet<String>> {
+        let entry_ids = entry_ids.to_vec();
+        self.read(move |conn| {
+            let mut result = HashSet::new();
+            for entry_id in entry_ids {
+                if query!(fetch (count: i64) in conn."SELECT COUNT(*) FROM pruned_entry WHERE feed_id = ? AND entry_id = ?"(
+                    feed_id, entry_id.as_str()
+                ))
+                .unwrap_or(0)
+                    != 0
+                {
+                    result.insert(entry_id);
+                }
+            }
+            Ok(result)
+        })
+        .await
+    }
+
+    /// Load every feed without its subscribers or entries, to be used by the scheduler.
+    async fn load_feeds(&self) -> Result<Vec<Feed>> {
+        let vault = self.vault.clone();
+        self.read(move |conn| {
+            let mut feeds = Vec::new();
+
+            query!(for (id: i64, url: String, credentials: Option<Vec<u8>>, last_check: i64, next_fetch: i64, scheduled_by: String, etag: Option<String>, last_modified: Option<String>, pruned_until: i64,
+                        polls: i64, not_modified: i64, entry_interval: Option<i64>, last_entry: Option<i64>,
+                        ttl: Option<i64>, update_period: Option<i64>, skip_hours: i64, skip_days: i64,
+                        interval: Option<i64>, ignore_cache: Option<i64>)
+                    in conn."SELECT id, url, credentials, last_check, next_check, scheduled_by, etag, last_modified, pruned_until,
+                        polls, not_modified, entry_interval, last_entry,
+                        ttl, update_period, skip_hours, skip_days, interval, ignore_cache FROM feed"() {
+                // Without the right key, the feed is still checked, but it will most likely fail.
+                let credentials = match (credentials, vault.as_ref()) {
+                    (Some(sealed), Some(vault)) => {
+                        let opened = vault.open(&url, &sealed);
+                        if opened.is_none() {
+                            warn!(feed.id = id, "stored credentials can't be opened with this key, checking the feed without them");
+                        }
+                        opened
+                    }
+                    (Some(_), None) => {
+                        warn!(feed.id = id, "no key to open the stored credentials with, checking the feed without them");
+                        None
+                    }
+                    (None, _) => None,
+                };
+                let last_fetch = Utc.timestamp_opt(last_check, 0).single().unwrap_or_else(|| {
+                    warn!(feed.id = id, last_check, "impossible last check, treating the feed as never checked");
+                    Utc.timestamp(0, 0)
+                });
+                feeds.push(Feed {
+                    id,
+                    url,
+                    credentials,
+                    // Loaded right before each check, along with the rest that may change meanwhile.
+                    options: RequestOptions::default(),
+                    users: Vec::new(),
+                    seen_entries: HashMap::new(),
+                    present_entries: HashSet::new(),
+                    renamed_entries: Vec::new(),
+                    pruned_until,
+                    last_fetch,
+                    next_fetch: feed::instant_at(next_fetch),
+                    scheduled_by: ScheduleSource::from_name(&scheduled_by),
+                    interval,
+                    ignore_cache: ignore_cache.unwrap_or(0) != 0,
+                    etag,
+                    last_modified,
+                    stats: Stats {
+                        polls,
+                        not_modified,
+                        entry_interval,
+                        last_entry,
+                    },
+                    hints: Hints {
+                        ttl,
+                        update_period,
+                        skip_hours: skip_hours as u32,
+                        skip_days: skip_days as u32,
+                    },
+                    hub: None,
+                    push_until: 0,
+                });
+            });
+
+            Ok(feeds)
+        })
+        .await
+    }
+
+    /// Load the subscribers and seen entries of a feed that's about to be checked.
+    ///
+    /// Returns `false` if the feed no longer exists or nobody is subscribed to it anymore.
+    async fn load_feed_state(&self, feed: &mut Feed) -> Result<bool> {
+        let feed_id = feed.id;
+        let (state, invalid) = self
+            .read(move |conn| {
+                let (pruned_until, interval, ignore_cache, proxy, headers, ca_bundle) = match query!(fetch (pruned_until: i64,
+                            interval: Option<i64>, ignore_cache: Option<i64>, proxy: Option<String>, headers: Option<String>, ca_bundle: Option<String>)
+                        in conn."SELECT pruned_until, interval, ignore_cache, proxy, headers, ca_bundle FROM feed WHERE id = ?"(feed_id))
+                {
+                    Some(row) => row,
+                    None => return Ok((None, Vec::new())),
+                };
+
+                let push_until = query!(fetch (lease_until: i64)
+                        in conn."SELECT lease_until FROM websub WHERE feed_id = ?"(feed_id))
+                .unwrap_or(0);
+
+                let mut users = Vec::new();
+                let mut invalid = Vec::new();
+                query!(for (rowid: i64, kind: i64, user: Vec<u8>, owner: i64, secret: Option<String>, notify_updates: i64)
+                        in conn."SELECT rowid, kind, user, owner, secret, notify_updates FROM subscriber WHERE feed_id = ?"(feed_id) {
+                    match Target::from_row(kind, &user, owner, secret) {
+                        Some(target) => users.push(Subscriber {
+                            target,
+                            notify_updates: notify_updates != 0,
+                        }),
+                        None => invalid.push(rowid),
+                    }
+                });
+                if users.is_empty() {
+                    return Ok((None, invalid));
+                }
+
+                let mut seen_entries = HashMap::new();
+                let mut last_seen = Vec::new();
+                query!(for (entry: String, title: Option<String>, link: Option<String>, updated: Option<i64>, hash: Option<String>, seen: i64)
+                        in conn."SELECT entry_id, title, link, updated, hash, last_seen FROM entry WHERE feed_id = ?"(feed_id) {
+                    last_seen.push((entry.clone(), seen));
+                    seen_entries.insert(entry, Fingerprint { title, link, updated, hash });
+                });
+                // Those seen by the last save, which pushed entries are added to.
+                let latest = last_seen.iter().map(|(_, seen)| *seen).max();
+                let present_entries = last_seen
+                    .into_iter()
+                    .filter(|(_, seen)| Some(*seen) == latest)
+                    .map(|(entry, _)| entry)
+                    .collect();
+
+                Ok((
+                    Some(FeedState {
+                        pruned_until,
+                        interval,
+                        ignore_cache: ignore_cache.unwrap_or(0) != 0,
+                        options: request_options(proxy, headers, ca_bundle),
+                        push_until,
+                        users,
+                        seen_entries,
+                        present_entries,
+                    }),
+                    invalid,
+                ))
+            })
+            .await?;
+
+        // The reader can't write, so they're moved out of the way afterwards.
+        if !invalid.is_empty() {
+            self.write(move |conn| {
+                for rowid in invalid {
+                    quarantine_subscriber(conn, rowid, "undecodable chat")?;
+                }
+                Ok(())
+            })
+            .await?;
         }
-        query!(conn."COMMIT");
-        Ok(())
-    }
-
-    pub fn cleanup_feeds(&self) -> sqlite::Result<()> {
-        let conn = self.0.lock().unwrap();
-        query!(conn."DELETE FROM feed AS f WHERE NOT EXISTS (
-            SELECT * FROM subscriber AS s WHERE s.feed_id = f.id)");
-        Ok(())
-    }
-
-    pub fn load_pending_feeds(&self) -> sqlite::Result<BinaryHeap<Feed>> {
-        let conn = self.0.lock().unwrap();
-        let mut feeds = HashMap::<i64, Feed>::new();
-        let now = Utc::now().timestamp();
-
-        query!(for (id: i64, url: String, last_check: i64, next_fetch: i64, etag: Option<String>)
-                in conn."SELECT id, url, last_check, next_check, etag FROM feed WHERE next_check < ?"(now) {
-            feeds.entry(id).or_insert_with(|| Feed {
-                url,
-                users: Vec::new(),
-                seen_entries: HashSet::new(),
-                last_fetch: Utc.timestamp_opt(last_check, 0).unwrap(),
-                next_fetch: {
-                    let now = Utc::now().timestamp();
-                    let delta = next_fetch - now;
-                    if delta < 0 {
-                        Instant::now() - Duration::from_secs(-delta as u64)
-                    } else {
-                        Instant::now() + Duration::from_secs(delta as u64)
+
+        let state = match state {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        feed.pruned_until = state.pruned_until;
+        // They may have changed while the feed was being checked.
+        feed.interval = state.interval;
+        feed.ignore_cache = state.ignore_cache;
+        feed.options = state.options;
+        feed.push_until = state.push_until;
+        feed.users = state.users;
+        feed.seen_entries = state.seen_entries;
+        feed.present_entries = state.present_entries;
+        Ok(true)
+    }
+
+    async fn get_websub(&self, feed_id: i64) -> Result<Option<Subscription>> {
+        self.read(move |conn| {
+            Ok(query!(fetch (url: String, hub: String, topic: String, secret: String, requested: i64)
+                    in conn."SELECT url, hub, topic, secret, requested FROM websub JOIN feed ON (id = feed_id) WHERE feed_id = ?"(feed_id))
+                .map(|(url, hub, topic, secret, requested)| Subscription { feed_id, url, hub, topic, secret, requested }))
+        })
+        .await
+    }
+
+    async fn set_websub_lease(&self, feed_id: i64, lease_until: i64) -> Result<()> {
+        self.write(move |conn| {
+            query!(conn."UPDATE websub SET lease_until = ? WHERE feed_id = ?"(lease_until, feed_id));
+            Ok(())
+        })
+        .await
+    }
+
+    async fn mark_websub_requested(&self, feed_id: i64, requested: i64) -> Result<()> {
+        self.write(move |conn| {
+            query!(conn."UPDATE websub SET requested = ? WHERE feed_id = ?"(requested, feed_id));
+            Ok(())
+        })
+        .await
+    }
+
+    /// Subscriptions whose lease ends before `until`, and that were not requested after `retry_before`.
+    async fn websub_to_renew(&self, until: i64, retry_before: i64) -> Result<Vec<Subscription>> {
+        self.read(move |conn| {
+            let mut result = Vec::new();
+            query!(for (feed_id: i64, url: String, hub: String, topic: String, secret: String, requested: i64)
+                    in conn."SELECT feed_id, url, hub, topic, secret, requested FROM websub JOIN feed ON (id = feed_id)
+                        WHERE lease_until < ? AND requested < ?"(until, retry_before) {
+                result.push(Subscription { feed_id, url, hub, topic, secret, requested });
+            });
+            Ok(result)
+        })
+        .await
+    }
+
+    /// Subscribe the target to the feed with the URL and credentials, if there is one already.
+    async fn try_add_subscriber(
+        &self,
+        url: &str,
+        credentials: Option<&Credentials>,
+        target: &Target,
+    ) -> Result<bool> {
+        let credentials_id = self.credentials_id(credentials)?;
+        let (url, target) = (url.to_string(), target.clone());
+        self.write(move |conn| {
+            if let Some(feed_id) = query!(fetch (id: i64) in conn."SELECT id FROM feed WHERE url = ? AND credentials_id = ?"(
+                url.as_str(), credentials_id.as_str()
+            )) {
+                query!(conn."INSERT INTO subscriber (feed_id, kind, user, owner, secret) VALUES (?, ?, ?, ?, ?)"(
+                    feed_id, target.kind(), target.data().as_slice(), target.owner(), target.secret()
+                ));
+                Ok(true)
+            } else {
+                Ok(false)
+            }
+        })
+        .await
+    }
+
+    /// Unsubscribe the target from the feeds with the URL, whichever credentials they use. Only the
+    /// target's owner can, and webhooks match whatever their secret is.
+    async fn try_del_subscriber(&self, url: &str, target: &Target) -> Result<bool> {
+        let (url, target) = (url.to_string(), target.clone());
+        self.write(move |conn| {
+            query!(conn."DELETE FROM subscriber WHERE kind = ? AND user = ? AND owner = ? AND feed_id IN (
+                SELECT id FROM feed WHERE url = ?
+            )"(target.kind(), target.data().as_slice(), target.owner(), url.as_str()));
+            if let Some(count) = query!(fetch (count: i64) in conn."SELECT changes()"()) {
+                Ok(count >= 1)
+            } else {
+                Ok(false)
+            }
+        })
+        .await
+    }
+
+    /// Flip whether the user wants to be notified about edited entries in the feed.
+    ///
+    /// Returns the new setting, or `None` if the user is not subscribed to the feed.
+    async fn toggle_notify_updates(&self, url: &str, target: &Target) -> Result<Option<bool>> {
+        let (url, target) = (url.to_string(), target.clone());
+        self.write(move |conn| {
+            query!(conn."UPDATE subscriber SET notify_updates = NOT notify_updates WHERE kind = ? AND user = ? AND owner = ? AND feed_id IN (
+                SELECT id FROM feed WHERE url = ?
+            )"(target.kind(), target.data().as_slice(), target.owner(), url.as_str()));
+            Ok(
+                query!(fetch (notify_updates: i64) in conn."SELECT notify_updates FROM subscriber WHERE kind = ? AND user = ? AND owner = ? AND feed_id IN (
+                    SELECT id FROM feed WHERE url = ?
+                )"(target.kind(), target.data().as_slice(), target.owner(), url.as_str()))
+                .map(|notify_updates| notify_updates != 0),
+            )
+        })
+        .await
+    }
+
+    /// The feeds with the URL, or only those the target is subscribed to if there is one.
+    async fn feed_ids(&self, url: &str, target: Option<&Target>) -> Result<Vec<i64>> {
+        let (url, target) = (url.to_string(), target.cloned());
+        self.read(move |conn| {
+            let mut ids = Vec::new();
+            match target {
+                Some(target) => {
+                    query!(for (id: i64) in conn."SELECT f.id FROM feed AS f WHERE f.url = ? AND EXISTS (
+                        SELECT * FROM subscriber AS s WHERE s.feed_id = f.id AND s.kind = ? AND s.user = ? AND s.owner = ?
+                    )"(url.as_str(), target.kind(), target.data().as_slice(), target.owner()) {
+                        ids.push(id);
+                    });
+                }
+                None => {
+                    query!(for (id: i64) in conn."SELECT id FROM feed WHERE url = ?"(url.as_str()) {
+                        ids.push(id);
+                    });
+                }
+            }
+            Ok(ids)
+        })
+        .await
+    }
+
+    /// Set (or clear) the feed's `/interval`. Returns `false` if there is no such feed.
+    async fn set_interval(&self, feed_id: i64, interval: Option<i64>) -> Result<bool> {
+        self.write(move |conn| {
+            query!(conn."UPDATE feed SET interval = ? WHERE id = ?"(interval, feed_id));
+            Ok(query!(fetch (count: i64) in conn."SELECT changes()"()).unwrap_or(0) != 0)
+        })
+        .await
+    }
+
+    async fn get_request_options(&self, feed_id: i64) -> Result<Option<RequestOptions>> {
+        self.read(move |conn| {
+            Ok(query!(fetch (proxy: Option<String>, headers: Option<String>, ca_bundle: Option<String>)
+                    in conn."SELECT proxy, headers, ca_bundle FROM feed WHERE id = ?"(feed_id))
+            .map(|(proxy, headers, ca_bundle)| request_options(proxy, headers, ca_bundle)))
+        })
+        .await
+    }
+
+    /// Replace the feed's request options, which are used from its next check on.
+    async fn set_request_options(&self, feed_id: i64, options: &RequestOptions) -> Result<()> {
+        let options = options.clone();
+        self.write(move |conn| {
+            query!(conn."UPDATE feed SET proxy = ?, headers = ?, ca_bundle = ? WHERE id = ?"(
+                options.proxy.as_deref(), options.headers_text().as_deref(), options.ca_bundle.as_deref(), feed_id
+            ));
+            Ok(())
+        })
+        .await
+    }
+
+    async fn toggle_ignore_cache(&self, feed_id: i64) -> Result<Option<bool>> {
+        self.write(move |conn| {
+            query!(conn."UPDATE feed SET ignore_cache = NOT COALESCE(ignore_cache, 0) WHERE id = ?"(feed_id));
+            Ok(
+                query!(fetch (ignore_cache: i64) in conn."SELECT ignore_cache FROM feed WHERE id = ?"(feed_id))
+                    .map(|ignore_cache| ignore_cache != 0),
+            )
+        })
+        .await
+    }
+
+    async fn add_to_digest(&self, address: &str, item: &Item) -> Result<()> {
+        let (address, item) = (address.to_string(), item.clone());
+        self.write(move |conn| {
+            query!(conn."INSERT INTO digest (address, feed_url, title, link, edited) VALUES (?, ?, ?, ?, ?)"(
+                address.as_str(), item.feed_url.as_str(), item.title.as_str(), item.link.as_str(), item.edited as i64
+            ));
+            Ok(())
+        })
+        .await
+    }
+
+    /// Every pending digest, with its items in the order they were added.
+    async fn load_digests(&self) -> Result<Vec<Digest>> {
+        self.read(|conn| {
+            let mut result = Vec::<Digest>::new();
+            query!(for (id: i64, address: String, feed_url: String, title: String, link: String, edited: i64)
+                    in conn."SELECT id, address, feed_url, title, link, edited FROM digest ORDER BY address, id"() {
+                let item = Item { feed_url, title, link, edited: edited != 0 };
+                match result.last_mut() {
+                    Some(digest) if digest.address == address => {
+                        digest.items.push(item);
+                        digest.last_id = id;
                     }
-                },
-                etag,
+                    _ => result.push(Digest { address, items: vec![item], last_id: id }),
+                }
             });
-        });
+            Ok(result)
+        })
+        .await
+    }
+
+    /// Forget the items of a digest that was sent. Items added since are kept.
+    async fn clear_digest(&self, digest: &Digest) -> Result<()> {
+        let (address, last_id) = (digest.address.clone(), digest.last_id);
+        self.write(move |conn| {
+            query!(conn."DELETE FROM digest WHERE address = ? AND id <= ?"(address.as_str(), last_id));
+            Ok(())
+        })
+        .await
+    }
 
-        query!(for (id: i64, entry: String)
-                in conn."SELECT id, entry_id FROM feed JOIN entry ON (id = feed_id) WHERE next_check < ?"(now) {
-            if let Some(feed) = feeds.get_mut(&id) {
-                feed.seen_entries.insert(entry);
+    async fn get_subscribers(&self, url: &str) -> Result<Vec<Subscriber>> {
+        let url = url.to_string();
+        self.read(move |conn| {
+            let mut result = Vec::new();
+            query!(for (kind: i64, user: Vec<u8>, owner: i64, secret: Option<String>, notify_updates: i64)
+                    in conn."SELECT kind, user, owner, secret, notify_updates FROM subscriber AS s
+                        JOIN feed AS f ON (f.id = s.feed_id)
+                        WHERE f.url = ?"(url.as_str()) {
+                if let Some(target) = Target::from_row(kind, &user, owner, secret) {
+                    result.push(Subscriber { target, notify_updates: notify_updates != 0 });
+                }
+            });
+            Ok(result)
+        })
+        .await
+    }
+
+    /// How many subscriptions the Telegram user owns, whatever they deliver to.
+    async fn count_subscriptions(&self, owner: i64) -> Result<usize> {
+        self.read(move |conn| {
+            Ok(query!(fetch (count: i64) in conn."SELECT COUNT(*) FROM subscriber WHERE owner = ?"(owner))
+            .unwrap_or(0) as usize)
+        })
+        .await
+    }
+
+    /// Ban the Telegram user. Returns `false` if they already were.
+    async fn ban(&self, user: i64, reason: Option<&str>) -> Result<bool> {
+        let reason = reason.map(String::from);
+        self.write(move |conn| {
+            query!(conn."INSERT OR IGNORE INTO ban (user, reason, banned_at) VALUES (?, ?, ?)"(
+                user, reason.as_deref(), Utc::now().timestamp()
+            ));
+            Ok(query!(fetch (count: i64) in conn."SELECT changes()"()).unwrap_or(0) != 0)
+        })
+        .await
+    }
+
+    /// Lift the ban on the Telegram user. Returns `false` if they weren't banned.
+    async fn unban(&self, user: i64) -> Result<bool> {
+        self.write(move |conn| {
+            query!(conn."DELETE FROM ban WHERE user = ?"(user));
+            Ok(query!(fetch (count: i64) in conn."SELECT changes()"()).unwrap_or(0) != 0)
+        })
+        .await
+    }
+
+    async fn is_banned(&self, user: i64) -> Result<bool> {
+        self.read(move |conn| {
+            Ok(
+                query!(fetch (user: i64) in conn."SELECT user FROM ban WHERE user = ?"(user))
+                    .is_some(),
+            )
+        })
+        .await
+    }
+
+    /// Every banned user as `(user, reason, banned_at)`.
+    async fn get_bans(&self) -> Result<Vec<(i64, Option<String>, i64)>> {
+        self.read(|conn| {
+            let mut result = Vec::new();
+            query!(for (user: i64, reason: Option<String>, banned_at: i64)
+                    in conn."SELECT user, reason, banned_at FROM ban ORDER BY banned_at"() {
+                result.push((user, reason, banned_at));
+            });
+            Ok(result)
+        })
+        .await
+    }
+
+    async fn get_user_feeds(&self, target: &Target) -> Result<Vec<String>> {
+        let target = target.clone();
+        self.read(move |conn| {
+            let mut result = Vec::new();
+            query!(for (url: String)
+                    in conn."SELECT url FROM feed AS f
+                        JOIN subscriber AS s ON (f.id = s.feed_id)
+                        WHERE s.kind = ? AND s.user = ? AND s.owner = ?"(
+                            target.kind(), target.data().as_slice(), target.owner()
+                        ) {
+                result.push(url);
+            });
+            Ok(result)
+        })
+        .await
+    }
+
+    /// The webhooks the Telegram user added, as `(feed URL, hook URL)`.
+    async fn get_user_hooks(&self, owner: i64) -> Result<Vec<(String, String)>> {
+        self.read(move |conn| {
+            let mut result = Vec::new();
+            query!(for (url: String, hook: String)
+                    in conn."SELECT f.url, s.user FROM feed AS f
+                        JOIN subscriber AS s ON (f.id = s.feed_id)
+                        WHERE s.kind = 1 AND s.owner = ?"(owner) {
+                result.push((url, hook));
+            });
+            Ok(result)
+        })
+        .await
+    }
+}
+
+/// The version the database is at, or 0 if it's new.
+fn read_version(conn: &sqlite::Connection) -> Result<i64> {
+    match conn.prepare("SELECT version FROM version") {
+        Ok(mut stmt) => {
+            if stmt.next()? != State::Row {
+                return Err(Error::MissingVersionError);
             }
-        });
+            Ok(stmt.read(0)?)
+        }
+        Err(err) => {
+            if err
+                .message
+                .as_ref()
+                .filter(|m| m.starts_with("no such table"))
+                .is_some()
+            {
+                Ok(0)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// Open the file with the settings every connection uses.
+fn open(name: &str) -> sqlite::Result<sqlite::Connection> {
+    let conn = sqlite::open(name)?;
+    // Readers see the last commit while a write is going on, instead of waiting for it to finish.
+    query!(conn."PRAGMA journal_mode = WAL");
+    query!(conn."PRAGMA busy_timeout = 5000");
+    query!(conn."PRAGMA foreign_keys = ON");
+    Ok(conn)
+}
+
+/// What `load_feed_state` reads, to be put into the feed once it's back from the blocking thread.
+struct FeedState {
+    pruned_until: i64,
+    interval: Option<i64>,
+    ignore_cache: bool,
+    options: RequestOptions,
+    push_until: i64,
+    users: Vec<Subscriber>,
+    seen_entries: HashMap<String, Fingerprint>,
+    present_entries: HashSet<String>,
+}
+
+/// The part of a feed that gets saved, copied out of it so that it can be saved on a blocking
+/// thread. Only the entries that were present are copied, as the rest have not changed.
+struct SavedFeed {
+    id: i64,
+    url: String,
+    last_check: i64,
+    next_check: i64,
+    scheduled_by: ScheduleSource,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    stats: Stats,
+    hints: Hints,
+    hub: Option<Hub>,
+    present_entries: Vec<(String, Fingerprint)>,
+    renamed_entries: Vec<(String, String)>,
+    users: Vec<Subscriber>,
+}
 
-        query!(for (id: i64, user: Vec<u8>)
-                in conn."SELECT id, user FROM feed JOIN subscriber ON (id = feed_id) WHERE next_check < ?"(now) {
-            if let Some(feed) = feeds.get_mut(&id) {
-                feed.users
-                    .push(PackedChat::from_bytes(&user).unwrap());
+impl SavedFeed {
+    fn of(feed: &Feed) -> Self {
+        Self {
+            id: feed.id,
+            url: feed.url.clone(),
+            last_check: feed.last_fetch.timestamp(),
+            next_check: feed.next_fetch_timestamp(),
+            scheduled_by: feed.scheduled_by,
+            etag: feed.etag.clone(),
+            last_modified: feed.last_modified.clone(),
+            stats: feed.stats.clone(),
+            hints: feed.hints.clone(),
+            hub: feed.hub.clone(),
+            present_entries: feed
+                .present_entries
+                .iter()
+                .filter_map(|id| Some((id.clone(), feed.seen_entries.get(id)?.clone())))
+                .collect(),
+            renamed_entries: feed.renamed_entries.clone(),
+            users: feed.users.clone(),
+        }
+    }
+}
+
+fn save_polling(conn: &sqlite::Connection, feed_id: i64, feed: &SavedFeed) -> sqlite::Result<()> {
+    query!(conn."UPDATE feed SET polls = ?, not_modified = ?, entry_interval = ?, last_entry = ?,
+        ttl = ?, update_period = ?, skip_hours = ?, skip_days = ? WHERE id = ?"(
+        feed.stats.polls,
+        feed.stats.not_modified,
+        feed.stats.entry_interval,
+        feed.stats.last_entry,
+        feed.hints.ttl,
+        feed.hints.update_period,
+        feed.hints.skip_hours as i64,
+        feed.hints.skip_days as i64,
+        feed_id
+    ));
+    Ok(())
+}
+
+/// Remember the hub advertised by the feed, starting over if it changed.
+fn save_hub(conn: &sqlite::Connection, feed_id: i64, feed: &SavedFeed) -> sqlite::Result<()> {
+    let hub = match feed.hub.as_ref() {
+        Some(hub) => hub,
+        None => return Ok(()),
+    };
+    query!(conn."INSERT OR IGNORE INTO websub (feed_id, hub, topic, secret) VALUES (?, ?, ?, ?)"(
+        feed_id, hub.hub.as_str(), hub.topic.as_str(), websub::new_secret().as_str()
+    ));
+    query!(conn."UPDATE websub SET hub = ?, topic = ?, lease_until = 0, requested = 0
+        WHERE feed_id = ? AND (hub != ? OR topic != ?)"(
+        hub.hub.as_str(), hub.topic.as_str(), feed_id, hub.hub.as_str(), hub.topic.as_str()
+    ));
+    Ok(())
+}
+
+fn request_options(
+    proxy: Option<String>,
+    headers: Option<String>,
+    ca_bundle: Option<String>,
+) -> RequestOptions {
+    RequestOptions {
+        proxy,
+        headers: headers
+            .map(|headers| RequestOptions::parse_headers_text(&headers))
+            .unwrap_or_default(),
+        ca_bundle,
+    }
+}
+
+/// Run the queries on a blocking thread, once the connection is free.
+///
+/// If they panic, the connection is left poisoned, and any queries after it fail too.
+async fn run<T, F>(conn: Arc<Mutex<sqlite::Connection>>, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&sqlite::Connection) -> sqlite::Result<T> + Send + 'static,
+{
+    task::spawn_blocking(move || match conn.lock() {
+        Ok(conn) => Ok(f(&conn)?),
+        Err(_) => Err(Error::UnavailableError(
+            "an earlier query panicked".to_string(),
+        )),
+    })
+    .await
+    .map_err(|e| Error::UnavailableError(e.to_string()))?
+}
+
+fn insert_feed(
+    conn: &sqlite::Connection,
+    feed: &SavedFeed,
+    sealed: Option<&[u8]>,
+    credentials_id: &str,
+) -> sqlite::Result<i64> {
+    query!(conn."INSERT INTO feed (url, credentials, credentials_id, last_check, next_check, scheduled_by, etag, last_modified)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)"(
+        feed.url.as_str(), sealed, credentials_id, feed.last_check,
+        feed.next_check, feed.scheduled_by.as_str(), feed.etag.as_deref(), feed.last_modified.as_deref()
+    ));
+    let feed_id =
+        query!(fetch (id: i64) in conn."SELECT last_insert_rowid()"()).ok_or_else(|| {
+            sqlite::Error {
+                code: None,
+                message: Some("the inserted feed has no ID".to_string()),
             }
-        });
+        })?;
+    save_polling(conn, feed_id, feed)?;
+    save_hub(conn, feed_id, feed)?;
 
-        Ok(feeds.into_iter().map(|(_, v)| v).collect())
+    save_present_entries(conn, feed_id, feed)?;
+    for sub in feed.users.iter() {
+        query!(conn."INSERT INTO subscriber (feed_id, kind, user, owner, secret, notify_updates) VALUES (?, ?, ?, ?, ?, ?)"(
+            feed_id, sub.target.kind(), sub.target.data().as_slice(), sub.target.owner(),
+            sub.target.secret(), sub.notify_updates as i64
+        ));
     }
+    Ok(feed_id)
+}
 
-    pub fn try_add_subscriber(&self, url: &str, user: &PackedChat) -> sqlite::Result<bool> {
-        let conn = self.0.lock().unwrap();
-        if let Some(feed_id) =
-            query!(fetch (id: i64) in conn."SELECT id FROM feed WHERE url = ?"(url))
-        {
-            query!(conn."INSERT INTO subscriber (feed_id, user) VALUES (?, ?)"(feed_id, user.to_bytes().as_slice()));
-            Ok(true)
-        } else {
-            Ok(false)
+fn save_feed(conn: &sqlite::Connection, feed: &SavedFeed) -> sqlite::Result<()> {
+    let feed_id = match query!(fetch (id: i64) in conn."SELECT id FROM feed WHERE id = ?"(feed.id))
+    {
+        Some(id) => id,
+        None => return Ok(()),
+    };
+    query!(conn."UPDATE feed SET last_check = ?, next_check = ?, scheduled_by = ?, etag = ?, last_modified = ? WHERE id = ?"(
+        feed.last_check, feed.next_check, feed.scheduled_by.as_str(),
+        feed.etag.as_deref(), feed.last_modified.as_deref(), feed_id
+    ));
+    save_polling(conn, feed_id, feed)?;
+    save_hub(conn, feed_id, feed)?;
+    save_present_entries(conn, feed_id, feed)?;
+    Ok(())
+}
+
+/// Store the entries present in the last fetch, bumping their `last_seen`.
+///
+/// Entries which were not present have not changed since they were last saved.
+fn save_present_entries(
+    conn: &sqlite::Connection,
+    feed_id: i64,
+    feed: &SavedFeed,
+) -> sqlite::Result<()> {
+    // Moved to their new ID rather than added anew, which would leave the old one behind to be
+    // matched again, and make them look like they were first seen now.
+    for (old_id, new_id) in feed.renamed_entries.iter() {
+        query!(conn."UPDATE OR REPLACE entry SET entry_id = ? WHERE feed_id = ? AND entry_id = ?"(
+            new_id.as_str(), feed_id, old_id.as_str()
+        ));
+        query!(conn."DELETE FROM delivery WHERE feed_id = ? AND entry_id = ?"(feed_id, old_id.as_str()));
+    }
+
+    // Not `feed.last_check`, which is reset after failed deliveries.
+    let now = Utc::now().timestamp();
+    for (entry_id, fingerprint) in feed.present_entries.iter() {
+        query!(conn."INSERT OR IGNORE INTO entry (feed_id, entry_id, first_seen, last_seen) VALUES (?, ?, ?, ?)"(
+            feed_id, entry_id.as_str(), now, now
+        ));
+        query!(conn."UPDATE entry SET title = ?, link = ?, updated = ?, hash = ?, last_seen = ? WHERE feed_id = ? AND entry_id = ?"(
+            fingerprint.title.as_deref(),
+            fingerprint.link.as_deref(),
+            fingerprint.updated,
+            fingerprint.hash.as_deref(),
+            now,
+            feed_id,
+            entry_id.as_str()
+        ));
+        // Saved as seen, so it won't be delivered again unless it changes.
+        query!(conn."DELETE FROM delivery WHERE feed_id = ? AND entry_id = ?"(feed_id, entry_id.as_str()));
+        query!(conn."DELETE FROM pruned_entry WHERE feed_id = ? AND entry_id = ?"(feed_id, entry_id.as_str()));
+    }
+    Ok(())
+}
+
+/// Move a subscriber which can't be decoded to the quarantine, so that it's not loaded again.
+///
+/// The feed goes away along with its last subscriber, so what's needed to add it back is kept too.
+fn quarantine_subscriber(
+    conn: &sqlite::Connection,
+    rowid: i64,
+    reason: &str,
+) -> sqlite::Result<()> {
+    let (feed_id, kind, user, owner, secret, notify_updates) = match query!(fetch (feed_id: i64, kind: i64, user: Vec<u8>, owner: i64, secret: Option<String>, notify_updates: i64)
+            in conn."SELECT feed_id, kind, user, owner, secret, notify_updates FROM subscriber WHERE rowid = ?"(rowid))
+    {
+        Some(row) => row,
+        None => return Ok(()),
+    };
+    warn!(feed.id = feed_id, kind, reason, "quarantining subscriber");
+    let (url, credentials) = match query!(fetch (url: String, credentials: Option<Vec<u8>>)
+            in conn."SELECT url, credentials FROM feed WHERE id = ?"(feed_id))
+    {
+        Some((url, credentials)) => (Some(url), credentials),
+        None => (None, None),
+    };
+    let data = serde_json::json!({
+        "feed_id": feed_id,
+        // Sealed for the feed's URL, like in the `feed` table.
+        "feed_url": url,
+        "feed_credentials": credentials,
+        "kind": kind,
+        "user": user,
+        "owner": owner,
+        "secret": secret,
+        "notify_updates": notify_updates,
+    })
+    .to_string();
+    query!(conn."INSERT INTO quarantine (source, data, reason, quarantined_at) VALUES ('subscriber', ?, ?, ?)"(
+        data.as_str(), reason, Utc::now().timestamp()
+    ));
+    query!(conn."DELETE FROM subscriber WHERE rowid = ?"(rowid));
+    Ok(())
+}
+
+fn check_integrity(conn: &sqlite::Connection, repair: bool) -> sqlite::Result<Integrity> {
+    let mut integrity = Integrity::default();
+    query!(for (message: String) in conn."PRAGMA integrity_check"() {
+        if message != "ok" {
+            integrity.corruption.push(message);
+        }
+    });
+
+    // Foreign keys were off for a while during upgrades, and aren't enforced by other tools.
+    for table in ["entry", "subscriber", "delivery", "websub", "pruned_entry"].iter() {
+        let orphaned = format!("FROM {} WHERE feed_id NOT IN (SELECT id FROM feed)", table);
+        let count = {
+            let mut stmt = conn.prepare(format!("SELECT COUNT(*) {}", orphaned))?;
+            stmt.next()?;
+            stmt.read::<i64>(0)?
+        };
+        if count != 0 {
+            integrity
+                .orphans
+                .push(format!("{} {} rows without a feed", count, table));
+            if repair {
+                conn.execute(format!("DELETE {}", orphaned))?;
+            }
         }
     }
 
-    pub fn try_del_subscriber(&self, url: &str, user: &PackedChat) -> sqlite::Result<bool> {
-        let conn = self.0.lock().unwrap();
-        query!(conn."DELETE FROM subscriber WHERE user = ? AND feed_id = (
-            SELECT id FROM feed WHERE url = ?
-        )"(user.to_bytes().as_slice(), url));
-        if let Some(count) = query!(fetch (count: i64) in conn."SELECT changes()"()) {
-            Ok(count == 1)
-        } else {
-            Ok(false)
+    let mut invalid = Vec::new();
+    query!(for (rowid: i64, feed_id: i64, kind: i64, user: Vec<u8>)
+            in conn."SELECT rowid, feed_id, kind, user FROM subscriber"() {
+        if Target::from_row(kind, &user, 0, None).is_none() {
+            integrity.invalid_chats.push(format!(
+                "subscriber {} of feed {}: {} bytes of kind {}", rowid, feed_id, user.len(), kind
+            ));
+            invalid.push(rowid);
         }
+    });
+    if repair {
+        for rowid in invalid {
+            quarantine_subscriber(conn, rowid, "undecodable chat")?;
+        }
+    }
+
+    // Repaired, feeds are checked right away, as if they never were, and entries are seen now.
+    let now = Utc::now().timestamp();
+    let past = now + MAX_CLOCK_SKEW;
+    let ahead = now + MAX_SCHEDULE_AHEAD;
+    query!(for (id: i64, url: String, last_check: i64, next_check: i64)
+            in conn."SELECT id, url, last_check, next_check FROM feed
+                WHERE last_check NOT BETWEEN 0 AND ? OR next_check NOT BETWEEN 0 AND ?"(past, ahead) {
+        integrity.impossible_timestamps.push(format!(
+            "feed {} ({}): checked {}, next {}",
+            id, url, string::format_timestamp(last_check), string::format_timestamp(next_check)
+        ));
+    });
+    query!(for (feed_id: i64, count: i64)
+            in conn."SELECT feed_id, COUNT(*) FROM entry
+                WHERE first_seen NOT BETWEEN 0 AND ? OR last_seen NOT BETWEEN 0 AND ? GROUP BY feed_id"(past, past) {
+        integrity
+            .impossible_timestamps
+            .push(format!("feed {}: {} entries seen at impossible times", feed_id, count));
+    });
+    if repair {
+        query!(conn."UPDATE feed SET last_check = 0 WHERE last_check NOT BETWEEN 0 AND ?"(past));
+        query!(conn."UPDATE feed SET next_check = ? WHERE next_check NOT BETWEEN 0 AND ?"(now, ahead));
+        query!(conn."UPDATE entry SET first_seen = ?, last_seen = ?
+            WHERE first_seen NOT BETWEEN 0 AND ? OR last_seen NOT BETWEEN 0 AND ?"(now, now, past, past));
     }
 
-    pub fn get_user_feeds(&self, user: &PackedChat) -> sqlite::Result<Vec<String>> {
-        let conn = self.0.lock().unwrap();
-        let mut result = Vec::new();
-        query!(for (url: String)
-                in conn."SELECT url FROM feed AS f
-                    JOIN subscriber AS s ON (f.id = s.feed_id)
-                    WHERE s.user = ?"(user.to_bytes().as_slice()) {
-            result.push(url);
+    Ok(integrity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_reads_dont_wait_for_writes() {
+        let path = std::env::temp_dir().join(format!("srsrssrsbot-{}.db", std::process::id()));
+        let name = path.to_str().unwrap().to_string();
+        let db = Database::new(&name).unwrap();
+        let mode = db
+            .read(|conn| {
+                let mut stmt = conn.prepare("PRAGMA journal_mode")?;
+                stmt.next()?;
+                stmt.read::<String>(0)
+            })
+            .await
+            .unwrap();
+        assert_eq!(mode, "wal");
+        assert!(db.ban(1, None).await.unwrap());
+
+        // Hold a write open on another thread, as a long transaction would.
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let writer = Arc::clone(&db.writer);
+        let handle = std::thread::spawn(move || {
+            let conn = writer.lock().unwrap();
+            conn.execute("BEGIN IMMEDIATE; UPDATE ban SET reason = 'spam'")
+                .unwrap();
+            locked_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+            conn.execute("COMMIT").unwrap();
         });
-        Ok(result)
+        locked_rx.recv().unwrap();
+        assert_eq!(db.get_bans().await.unwrap()[0].1, None);
+        release_tx.send(()).unwrap();
+        handle.join().unwrap();
+        assert_eq!(db.get_bans().await.unwrap()[0].1.as_deref(), Some("spam"));
+
+        drop(db);
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = std::fs::remove_file(format!("{}{}", name, suffix));
+        }
+    }
+
+    #[tokio::test]
+    async fn check_bad_versions_are_errors() {
+        let path =
+            std::env::temp_dir().join(format!("srsrssrsbot-{}-version.db", std::process::id()));
+        let name = path.to_str().unwrap().to_string();
+
+        let db = Database::new(&name).unwrap();
+        db.write(|conn| {
+            query!(conn."UPDATE version SET version = ?"(VERSION + 1));
+            Ok(())
+        })
+        .await
+        .unwrap();
+        drop(db);
+        match Database::new(&name) {
+            Err(Error::TooNewError(version)) => assert_eq!(version, VERSION + 1),
+            other => panic!("expected a version error, got {:?}", other.map(|_| ())),
+        }
+
+        sqlite::open(&name)
+            .unwrap()
+            .execute("DELETE FROM version")
+            .unwrap();
+        assert!(matches!(
+            Database::new(&name),
+            Err(Error::MissingVersionError)
+        ));
+
+        for suffix in ["", "-wal", "-shm"].iter() {
+            let _ = std::fs::remove_file(format!("{}{}", name, suffix));
+        }
+    }
+
+    #[tokio::test]
+    async fn check_pruned_entries_remembered() {
+        let db = Database::new(":memory:").unwrap();
+        db.write(|conn| {
+            query!(conn."INSERT INTO feed (id, url, last_check, next_check) VALUES (1, 'https://example.com/atom.xml', 0, 0)");
+            query!(conn."INSERT INTO subscriber (feed_id, kind, user) VALUES (1, 3, '!room:example.com')");
+            // Entry 1 has no date, and dropped out of the feed long before the last check.
+            query!(conn."INSERT INTO entry (feed_id, entry_id, first_seen, last_seen) VALUES (1, '1', 100, 100)");
+            query!(conn."INSERT INTO entry (feed_id, entry_id, first_seen, last_seen) VALUES (1, '2', 100, 200)");
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(db.prune_entries(150).await.unwrap(), 1);
+        let ids = vec!["1".to_string(), "2".to_string(), "3".to_string()];
+        assert_eq!(
+            db.pruned_entries(1, &ids).await.unwrap(),
+            vec!["1".to_string()].into_iter().collect()
+        );
+
+        let mut feed = db.load_feeds().await.unwrap().remove(0);
+        assert!(db.load_feed_state(&mut feed).await.unwrap());
+        assert_eq!(feed.pruned_until, 100);
+        assert!(!feed.seen_entries.contains_key("1"));
+
+        // Seen again, it's an entry like any other.
+        feed.seen_entries
+            .insert("1".to_string(), feed.seen_entries["2"].clone());
+        feed.present_entries.insert("1".to_string());
+        db.update_feed(&feed).await.unwrap();
+        assert!(db.pruned_entries(1, &ids).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn check_panics_are_errors() {
+        let db = Database::new(":memory:").unwrap();
+        let result = db
+            .write(|_| -> sqlite::Result<()> { panic!("query panicked") })
+            .await;
+        assert!(matches!(result, Err(Error::UnavailableError(_))));
+        assert!(matches!(
+            db.get_bans().await,
+            Err(Error::UnavailableError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn check_corrupted_rows_are_quarantined() {
+        let db = Database::new(":memory:").unwrap();
+        let data = Target::Matrix {
+            room: "!room:example.com".into(),
+        }
+        .data();
+        db.write(move |conn| {
+            query!(conn."INSERT INTO feed (id, url, last_check, next_check) VALUES (1, 'https://example.com/feed', ?, ?)"(
+                i64::MAX, i64::MAX
+            ));
+            query!(conn."INSERT INTO subscriber (feed_id, kind, user) VALUES (1, 0, x'00')");
+            query!(conn."INSERT INTO subscriber (feed_id, kind, user) VALUES (1, 3, ?)"(data.as_slice()));
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        let mut feeds = db.load_feeds().await.unwrap();
+        assert_eq!(feeds.len(), 1);
+        assert_eq!(feeds[0].last_fetch, Utc.timestamp(0, 0));
+        assert!(db.load_feed_state(&mut feeds[0]).await.unwrap());
+        assert_eq!(feeds[0].users.len(), 1);
+        assert!(matches!(
+            &feeds[0].users[0].target,
+            Target::Matrix { room } if room == "!room:example.com"
+        ));
+
+        let (quarantined, subscribers) = db
+            .read(|conn| {
+                Ok((
+                    query!(fetch (count: i64) in conn."SELECT COUNT(*) FROM quarantine WHERE source = 'subscriber'"()).unwrap(),
+                    query!(fetch (count: i64) in conn."SELECT COUNT(*) FROM subscriber"()).unwrap(),
+                ))
+            })
+            .await
+            .unwrap();
+        assert_eq!((quarantined, subscribers), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn check_quarantined_feeds_can_be_restored() {
+        let db = Database::new(":memory:").unwrap();
+        db.write(|conn| {
+            query!(conn."INSERT INTO feed (id, url, last_check, next_check) VALUES (1, 'https://example.com/feed', 0, 0)");
+            query!(conn."INSERT INTO subscriber (feed_id, kind, user) VALUES (1, 0, x'00')");
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        // Without subscribers left, the feed itself is gone, but not where it was.
+        let mut feed = db.load_feeds().await.unwrap().remove(0);
+        assert!(!db.load_feed_state(&mut feed).await.unwrap());
+        db.cleanup_feeds().await.unwrap();
+        assert!(db.load_feeds().await.unwrap().is_empty());
+
+        let data = db
+            .read(|conn| {
+                Ok(query!(fetch (data: String) in conn."SELECT data FROM quarantine"()).unwrap())
+            })
+            .await
+            .unwrap();
+        let data = serde_json::from_str::<serde_json::Value>(&data).unwrap();
+        assert_eq!(data["feed_url"], "https://example.com/feed");
+        assert_eq!(data["feed_credentials"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn check_integrity_repairs() {
+        let db = Database::new(":memory:").unwrap();
+        db.write(|conn| {
+            query!(conn."PRAGMA foreign_keys = OFF");
+            query!(conn."INSERT INTO feed (id, url, last_check, next_check) VALUES (1, 'https://example.com/feed', -1, 0)");
+            query!(conn."INSERT INTO entry (feed_id, entry_id, last_seen) VALUES (1, 'a', ?)"(i64::MAX));
+            query!(conn."INSERT INTO entry (feed_id, entry_id) VALUES (2, 'b')");
+            query!(conn."INSERT INTO subscriber (feed_id, kind, user) VALUES (1, 0, x'00')");
+            query!(conn."PRAGMA foreign_keys = ON");
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        for &repair in [false, false, true].iter() {
+            let integrity = db.check_integrity(repair).await.unwrap();
+            assert!(integrity.corruption.is_empty());
+            assert_eq!(integrity.orphans.len(), 1);
+            assert_eq!(integrity.invalid_chats.len(), 1);
+            assert_eq!(integrity.impossible_timestamps.len(), 2);
+        }
+        assert!(db.check_integrity(false).await.unwrap().is_ok());
     }
 }
@@ -0,0 +1,199 @@
+use crate::feed::Change;
+use crate::string;
+use crate::transport::Error;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+
+/// The parts of an entry that go into an email, which is also what's kept for digests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub feed_url: String,
+    pub title: String,
+    pub link: String,
+    pub edited: bool,
+}
+
+/// Items waiting to be sent together to the same address.
+#[derive(Debug)]
+pub struct Digest {
+    pub address: String,
+    pub items: Vec<Item>,
+    /// Greatest row ID of the items, so that only these are cleared once sent.
+    pub last_id: i64,
+}
+
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Item {
+    pub fn of(feed_url: &str, change: &Change) -> Self {
+        Self {
+            feed_url: feed_url.to_string(),
+            title: string::entry_title(&change.entry),
+            link: string::entry_link(&change.entry),
+            edited: change.updated,
+        }
+    }
+}
+
+pub fn valid_address(address: &str) -> bool {
+    address.parse::<Mailbox>().is_ok()
+}
+
+impl Mailer {
+    /// Send through the relay at `host` over TLS, logging in if there are credentials.
+    pub fn relay(
+        host: &str,
+        port: Option<u16>,
+        credentials: Option<(&str, &str)>,
+        from: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::relay(host)?;
+        if let Some(port) = port {
+            builder = builder.port(port);
+        }
+        if let Some((user, pass)) = credentials {
+            builder = builder.credentials(Credentials::new(user.to_string(), pass.to_string()));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            from: from.parse()?,
+        })
+    }
+
+    /// Send to `host` without encryption or logging in, which is only fine for a local server.
+    pub fn unencrypted(
+        host: &str,
+        port: u16,
+        from: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            transport: AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+                .port(port)
+                .build(),
+            from: from.parse()?,
+        })
+    }
+
+    /// Send the items in a single email, with both a plain-text and an HTML body.
+    ///
+    /// Permanent SMTP errors (such as a non-existing mailbox) are not worth retrying.
+    pub async fn send(&self, to: &str, items: &[Item]) -> Result<(), Error> {
+        let to = to
+            .parse::<Mailbox>()
+            .map_err(|e| Error::Rejected(e.to_string()))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(string::email_subject(items))
+            .multipart(MultiPart::alternative_plain_html(
+                string::email_text(items),
+                string::email_html(items),
+            ))
+            .map_err(|e| Error::Rejected(e.to_string()))?;
+
+        match self.transport.send(message).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_permanent() => Err(Error::Rejected(e.to_string())),
+            Err(e) => Err(Error::Other(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// Accept a single SMTP session, and return the lines of the first message sent in it.
+    async fn sink() -> (u16, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read, mut write) = stream.into_split();
+            let mut lines = BufReader::new(read).lines();
+            let mut data = Vec::new();
+            let mut in_data = false;
+
+            write.write_all(b"220 sink\r\n").await.unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let reply = if in_data {
+                    if line != "." {
+                        data.push(line);
+                        continue;
+                    }
+                    "250 queued"
+                } else {
+                    match line.get(..4).unwrap_or("").to_uppercase().as_str() {
+                        "EHLO" | "HELO" => "250 sink",
+                        "DATA" => {
+                            in_data = true;
+                            "354 go ahead"
+                        }
+                        "QUIT" => "221 bye",
+                        _ => "250 ok",
+                    }
+                };
+                write
+                    .write_all(format!("{}\r\n", reply).as_bytes())
+                    .await
+                    .unwrap();
+                if reply == "250 queued" {
+                    break;
+                }
+            }
+            data
+        });
+        (port, handle)
+    }
+
+    #[tokio::test]
+    async fn check_email_multipart_sent() {
+        let (port, handle) = sink().await;
+        let mailer = Mailer::unencrypted("127.0.0.1", port, "bot@example.com").unwrap();
+
+        let items = vec![
+            Item {
+                feed_url: "https://example.com/atom.xml".to_string(),
+                title: "Entry 1".to_string(),
+                link: "https://example.com/1".to_string(),
+                edited: false,
+            },
+            Item {
+                feed_url: "https://example.com/atom.xml".to_string(),
+                title: "Entry <2>".to_string(),
+                link: "https://example.com/2".to_string(),
+                edited: true,
+            },
+        ];
+        mailer.send("reader@example.com", &items).await.unwrap();
+
+        let data = handle.await.unwrap().join("\n");
+        assert!(data.contains("To: reader@example.com"));
+        assert!(data.contains(&format!("Subject: {}", string::email_subject(&items))));
+        assert!(data.contains("multipart/alternative"));
+        assert!(data.contains("text/plain"));
+        assert!(data.contains("text/html"));
+        assert!(data.contains("https://example.com/2"));
+        assert!(data.contains("Entry &lt;2&gt;"));
+    }
+
+    #[test]
+    fn check_only_web_links() {
+        let item = |link: &str| Item {
+            feed_url: "https://example.com/atom.xml".to_string(),
+            title: "Entry".to_string(),
+            link: link.to_string(),
+            edited: false,
+        };
+        let html =
+            string::email_html(&[item("https://example.com/1"), item("javascript:alert(1)")]);
+        assert!(html.contains("<a href=\"https://example.com/1\">Entry</a>"));
+        assert!(!html.contains("javascript"));
+    }
+}
@@ -1,159 +1,713 @@
+use crate::cache::{self, CachePolicy};
+use crate::credentials::Credentials;
+use crate::hints::Hints;
+use crate::metrics::metrics;
+use crate::net;
+use crate::request::RequestOptions;
+use crate::target::Target;
+use crate::websub::Hub;
 use chrono::{DateTime, Duration, NaiveDateTime, Utc};
-use grammers_client::types::chat::PackedChat;
+use once_cell::sync::Lazy;
 use reqwest::{header, StatusCode};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::{collections::HashSet, fmt};
 use tokio::time::Instant;
+use tracing::debug;
+
+#[derive(Debug, Clone)]
+pub struct Subscriber {
+    pub target: Target,
+    /// Whether the subscriber also wants to hear about edits to entries they were already notified about.
+    pub notify_updates: bool,
+}
+
+/// What we remember about an entry to tell whether it changed since we last saw it.
+///
+/// Rows stored before fingerprints existed have every field set to `None`, and are silently
+/// refreshed the next time the entry is seen rather than being reported as updated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub title: Option<String>,
+    pub link: Option<String>,
+    pub updated: Option<i64>,
+    pub hash: Option<String>,
+}
+
+/// An entry that should be notified, either because it's new or because its contents changed.
+#[derive(Debug)]
+pub struct Change {
+    pub entry: feed_rs::model::Entry,
+    pub updated: bool,
+    /// The fingerprint the entry had before it changed, if it was seen before.
+    pub previous: Option<Fingerprint>,
+}
 
 #[derive(Debug)]
 pub struct Feed {
+    /// Row ID in the database, or 0 if the feed hasn't been loaded from there.
+    pub id: i64,
     pub url: String,
-    pub users: Vec<PackedChat>,
-    pub seen_entries: HashSet<String>,
+    /// Attached to every request for the feed. Feeds with the same URL but different credentials
+    /// are separate feeds.
+    pub credentials: Option<Credentials>,
+    /// Proxy, headers and TLS settings, loaded along with the subscribers before every check.
+    pub options: RequestOptions,
+    pub users: Vec<Subscriber>,
+    pub seen_entries: HashMap<String, Fingerprint>,
+    /// Entries which were present the last time the feed was fetched, along with those pushed since.
+    pub present_entries: HashSet<String>,
+    /// Entries matched under a new ID by the last check, as `(old ID, new ID)`, for the stored
+    /// ones to be renamed along.
+    pub renamed_entries: Vec<(String, String)>,
+    /// Entries first seen up to this timestamp may have been pruned from `seen_entries`, or 0 if
+    /// none ever were.
+    ///
+    /// Unknown entries may then be pruned entries that reappeared (for example, because the feed
+    /// reordered its items), which the storage has to be asked about before notifying them.
+    pub pruned_until: i64,
     pub last_fetch: DateTime<Utc>,
     pub next_fetch: Instant,
+    /// What decided `next_fetch`, to tell why a feed is checked as often as it is.
+    pub scheduled_by: ScheduleSource,
+    /// Seconds between checks chosen with `/interval`, replacing whatever the hints would say.
+    pub interval: Option<i64>,
+    /// Whether the server's caching headers are ignored, for servers which claim feeds stay fresh
+    /// for much longer than they do.
+    pub ignore_cache: bool,
+    /// Validators sent by the server, kept verbatim to be echoed back in conditional requests.
     pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub stats: Stats,
+    pub hints: Hints,
+    /// The WebSub hub advertised by the feed, if any.
+    pub hub: Option<Hub>,
+    /// Timestamp until which a hub has confirmed it will push the feed's updates to us.
+    pub push_until: i64,
+}
+
+/// The rule or hint which had the last word on when a feed is checked next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduleSource {
+    /// Nothing was known about the feed, so the default delay was used.
+    Default,
+    /// The interval set with `/interval`.
+    Interval,
+    /// How often the feed posted in the past.
+    History,
+    /// The feed's `sy:updatePeriod`.
+    UpdatePeriod,
+    /// The server's caching headers.
+    Server(cache::Source),
+    /// The feed's `<ttl>`.
+    Ttl,
+    /// A WebSub hub is pushing the updates.
+    Push,
+    /// Clamped to the minimum delay.
+    Minimum,
+    /// Clamped to the maximum delay.
+    Maximum,
+    /// Moved past the feed's `<skipHours>` or `<skipDays>`.
+    Skip,
+    /// Checking (or saving) the feed failed, so it's tried again soon.
+    Retry,
+    /// An admin asked for it to be checked right away.
+    Forced,
+}
+
+/// Bounds on the delay between checks, in seconds, for the schedule and the `/interval` command.
+///
+/// A bad-behaved server might put an absurd amount for the `max-age`, and then we would never
+/// check that feed again. If the server returns a very small value (or even in the past), the
+/// minimum is used instead.
+pub const MIN_FETCH_DELAY: i64 = 60;
+pub const MAX_FETCH_DELAY: i64 = 24 * 60 * 60;
+
+/// How many times to poll a feed, on average, between two of its entries.
+///
+/// Polling more than once per entry means new entries are noticed within a fraction of the
+/// interval at which the feed posts.
+const POLLS_PER_ENTRY: i64 = 4;
+
+/// Posting history of a feed, used to adapt how often it's polled.
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// How many times the feed was checked.
+    pub polls: i64,
+    /// How many of those checks got a "304 Not Modified".
+    pub not_modified: i64,
+    /// Average amount of seconds between new entries.
+    pub entry_interval: Option<i64>,
+    /// Timestamp of the last time a new entry was found.
+    pub last_entry: Option<i64>,
 }
 
 #[derive(Debug)]
 pub enum Error {
     ReadError(reqwest::Error),
     ParseError(feed_rs::parser::ParseFeedError),
-    MalformedHeader(header::HeaderName),
-}
-
-fn header(headers: &header::HeaderMap, key: header::HeaderName) -> Result<Option<&str>, Error> {
-    Ok(match headers.get(&key) {
-        Some(v) => Some(v.to_str().map_err(|_| Error::MalformedHeader(key))?),
-        None => None,
-    })
-}
-
-fn find_expiry(headers: &header::HeaderMap) -> Result<Instant, Error> {
-    // Can't use constants here, `Duration::seconds` is not a const-fn as of 0.4.19.
-    //
-    // Maximum cache delay we're willing to accept.
-    //
-    // A bad-behaved server might put an absurd amount for the `max-age`, and then we would never
-    // check that feed again.
-    let max_fetch_delay: Duration = Duration::seconds(24 * 60 * 60);
-
-    // If the server returns a very small value (or even in the past), use this instead.
-    let min_fetch_delay: Duration = Duration::seconds(60);
-
-    // If the server does not have any max age or expiration for the feed, use a default delay.
-    let default_fetch_delay: Duration = Duration::seconds(10 * 60);
-
-    let now = Utc::now();
-    let delay = if let Some(cache_control) = header(headers, header::CACHE_CONTROL)? {
-        let seconds = cache_control.split(",").find_map(|directive| {
-            let mut parts = directive.split("=");
-            let key = parts.next()?;
-            let value = parts.next()?;
-            if key.to_lowercase() == "max-age" {
-                Some(value)
-            } else {
-                None
+    /// The feed's proxy or CA bundle can't be used.
+    OptionsError(String),
+}
+
+/// A cache validator from the response. Values we couldn't echo back as-is are ignored rather than
+/// failing the fetch, as they only cost a full download.
+fn validator(headers: &header::HeaderMap, key: header::HeaderName) -> Option<String> {
+    headers
+        .get(&key)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+/// Send the request, recording how long it took, and fail if the status is not a success.
+async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response, Error> {
+    let start = Instant::now();
+    let resp = request.send().await;
+    let elapsed = start.elapsed();
+    let status = resp.as_ref().ok().map(|resp| resp.status().as_u16());
+    if let Some(status) = status {
+        debug!(
+            http.status = status,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "got response"
+        );
+    }
+    metrics().fetched(status, elapsed);
+    Ok(resp?.error_for_status()?)
+}
+
+/// 64-bit FNV-1a. Used over `DefaultHasher` because the result is persisted, and the standard
+/// library makes no promises about its hash being stable across releases.
+fn fnv1a(bytes: impl IntoIterator<Item = u8>, mut hash: u64) -> u64 {
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl Fingerprint {
+    pub fn of(entry: &feed_rs::model::Entry) -> Self {
+        let title = entry.title.as_ref().map(|t| t.content.clone());
+        let link = entry.links.iter().next().map(|link| link.href.clone());
+        let updated = entry.updated.or(entry.published).map(|d| d.timestamp());
+
+        // Separate fields with a zero byte so that moving text between fields changes the hash.
+        let mut hash = 0xcbf29ce484222325;
+        for text in [
+            title.as_deref(),
+            link.as_deref(),
+            entry.summary.as_ref().map(|t| t.content.as_str()),
+            entry.content.as_ref().and_then(|c| c.body.as_deref()),
+        ]
+        .iter()
+        {
+            hash = fnv1a(text.unwrap_or("").bytes().chain(Some(0)), hash);
+        }
+
+        Self {
+            title,
+            link,
+            updated,
+            hash: Some(format!("{:016x}", hash)),
+        }
+    }
+
+    fn is_legacy(&self) -> bool {
+        self.hash.is_none()
+    }
+}
+
+impl ScheduleSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Interval => "interval",
+            Self::History => "history",
+            Self::UpdatePeriod => "update-period",
+            Self::Server(source) => source.as_str(),
+            Self::Ttl => "ttl",
+            Self::Push => "push",
+            Self::Minimum => "minimum",
+            Self::Maximum => "maximum",
+            Self::Skip => "skip",
+            Self::Retry => "retry",
+            Self::Forced => "forced",
+        }
+    }
+
+    /// The opposite of `as_str`, with anything unknown becoming `Default`.
+    pub fn from_name(value: &str) -> Self {
+        match value {
+            "interval" => Self::Interval,
+            "history" => Self::History,
+            "update-period" => Self::UpdatePeriod,
+            "max-age" => Self::Server(cache::Source::MaxAge),
+            "s-maxage" => Self::Server(cache::Source::SMaxAge),
+            "expires" => Self::Server(cache::Source::Expires),
+            "ttl" => Self::Ttl,
+            "push" => Self::Push,
+            "minimum" => Self::Minimum,
+            "maximum" => Self::Maximum,
+            "skip" => Self::Skip,
+            "retry" => Self::Retry,
+            "forced" => Self::Forced,
+            _ => Self::Default,
+        }
+    }
+}
+
+impl fmt::Display for ScheduleSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Stats {
+    /// Estimate the interval between entries from their publication dates.
+    fn from_entries(entries: &[feed_rs::model::Entry]) -> Self {
+        let mut dates = entries
+            .iter()
+            .filter_map(|entry| entry.published.or(entry.updated))
+            .map(|date| date.timestamp())
+            .collect::<Vec<_>>();
+        dates.sort_unstable();
+
+        let entry_interval = match (dates.first(), dates.last()) {
+            (Some(first), Some(last)) if dates.len() > 1 && last > first => {
+                Some((last - first) / (dates.len() as i64 - 1))
             }
-        });
-        if let Some(seconds) = seconds {
-            Duration::seconds(
-                seconds
-                    .parse::<i64>()
-                    .map_err(|_| Error::MalformedHeader(header::CACHE_CONTROL))?,
-            )
-        } else {
-            default_fetch_delay
+            _ => None,
+        };
+
+        Self {
+            polls: 0,
+            not_modified: 0,
+            entry_interval,
+            last_entry: dates.last().copied(),
         }
-    } else if let Some(expiry) = header(headers, header::EXPIRES)? {
-        let expires = DateTime::parse_from_rfc2822(expiry)
-            .map(DateTime::<Utc>::from)
-            .map_err(|_| Error::MalformedHeader(header::EXPIRES))?;
+    }
 
-        expires - now
-    } else {
-        default_fetch_delay
-    };
+    fn record_poll(&mut self, not_modified: bool) {
+        // Halve the counters every so often so that the share reflects recent behaviour.
+        if self.polls >= 1000 {
+            self.polls /= 2;
+            self.not_modified /= 2;
+        }
+        self.polls += 1;
+        if not_modified {
+            self.not_modified += 1;
+        }
+    }
+
+    fn record_entries(&mut self, new_entries: usize, now: i64) {
+        if new_entries != 0 {
+            if let Some(last_entry) = self.last_entry {
+                let observed = (now - last_entry).max(0) / new_entries as i64;
+                self.entry_interval = Some(match self.entry_interval {
+                    Some(interval) => (interval * 3 + observed) / 4,
+                    None => observed,
+                });
+            }
+            self.last_entry = Some(now);
+        }
+    }
 
-    // Can't panic, `max(MIN_FETCH_DELAY)` will make it positive, so `to_std()` succeeds.
-    Ok(Instant::now()
-        + delay
-            .min(max_fetch_delay)
-            .max(min_fetch_delay)
-            .to_std()
-            .unwrap())
+    /// How often the feed should be polled judging from its history alone.
+    fn adaptive_delay(&self, default_delay: Duration) -> Option<Duration> {
+        if let Some(interval) = self.entry_interval {
+            Some(Duration::seconds(interval / POLLS_PER_ENTRY))
+        } else if self.polls >= 10 {
+            // No idea how often it posts, but if it rarely changes, there's no rush.
+            let unchanged = self.not_modified as f64 / self.polls as f64;
+            Some(Duration::seconds(
+                (default_delay.num_seconds() as f64 * (1.0 + 3.0 * unchanged)) as i64,
+            ))
+        } else {
+            None
+        }
+    }
 }
 
 impl Feed {
-    pub async fn new(http: &reqwest::Client, url: &str, user: PackedChat) -> Result<Self, Error> {
-        let resp = http.get(url).send().await?.error_for_status()?;
+    pub async fn new(
+        http: &reqwest::Client,
+        url: &str,
+        credentials: Option<Credentials>,
+        target: Target,
+    ) -> Result<Self, Error> {
+        let mut feed = Self::fetch(http, url, credentials).await?;
+        feed.users.push(Subscriber {
+            target,
+            notify_updates: false,
+        });
+        Ok(feed)
+    }
+
+    /// Fetch the feed for the first time, without anyone subscribed to it.
+    pub async fn fetch(
+        http: &reqwest::Client,
+        url: &str,
+        credentials: Option<Credentials>,
+    ) -> Result<Self, Error> {
+        let mut request = http.get(url);
+        if let Some(credentials) = credentials.as_ref() {
+            request = credentials.apply(request);
+        }
+        let resp = send(request).await?;
+        Self::from_response(url, credentials, resp).await
+    }
+
+    /// Make the feed out of the response to its first fetch, which the caller may have looked into.
+    pub async fn from_response(
+        url: &str,
+        credentials: Option<Credentials>,
+        resp: reqwest::Response,
+    ) -> Result<Self, Error> {
         let last_fetch = Utc::now();
-        let next_fetch = find_expiry(resp.headers())?;
-        let etag = header(resp.headers(), header::ETAG)?.map(String::from);
+        let freshness = CachePolicy::parse(resp.headers()).freshness();
+        let etag = validator(resp.headers(), header::ETAG);
+        let last_modified = validator(resp.headers(), header::LAST_MODIFIED);
         let xml = resp.bytes().await?;
 
         let feed = feed_rs::parser::parse(xml.as_ref())?;
+        let hints = Hints::parse(xml.as_ref(), &feed);
+        let stats = Stats::from_entries(&feed.entries);
+        let hub = Hub::find(&feed, url);
+        let present_entries = feed
+            .entries
+            .iter()
+            .map(|entry| entry.id.clone())
+            .collect::<HashSet<_>>();
         let seen_entries = feed
             .entries
             .into_iter()
-            .map(|entry| entry.id)
-            .collect::<HashSet<_>>();
+            .map(|entry| {
+                let fingerprint = Fingerprint::of(&entry);
+                (entry.id, fingerprint)
+            })
+            .collect::<HashMap<_, _>>();
 
-        Ok(Self {
+        let mut feed = Self {
+            id: 0,
             url: url.to_string(),
-            users: vec![user],
+            credentials,
+            options: RequestOptions::default(),
+            users: Vec::new(),
             seen_entries,
+            present_entries,
+            renamed_entries: Vec::new(),
+            pruned_until: 0,
             last_fetch,
-            next_fetch,
+            next_fetch: Instant::now(),
+            scheduled_by: ScheduleSource::Default,
+            interval: None,
+            ignore_cache: false,
             etag,
-        })
+            last_modified,
+            stats,
+            hints,
+            hub,
+            push_until: 0,
+        };
+        feed.schedule(freshness);
+        Ok(feed)
     }
 
-    pub async fn check(
-        &mut self,
-        http: &reqwest::Client,
-    ) -> Result<Vec<feed_rs::model::Entry>, Error> {
-        let mut request = http
-            .get(&self.url)
-            .header(header::IF_MODIFIED_SINCE, self.last_fetch.to_rfc2822());
+    /// A feed which was never checked nor subscribed to, for the tests to fill in what they need.
+    #[cfg(test)]
+    pub fn for_test(url: &str) -> Self {
+        Self {
+            id: 0,
+            url: url.to_string(),
+            credentials: None,
+            options: RequestOptions::default(),
+            users: Vec::new(),
+            seen_entries: HashMap::new(),
+            present_entries: HashSet::new(),
+            renamed_entries: Vec::new(),
+            pruned_until: 0,
+            last_fetch: Utc::now(),
+            next_fetch: Instant::now(),
+            scheduled_by: ScheduleSource::Default,
+            interval: None,
+            ignore_cache: false,
+            etag: None,
+            last_modified: None,
+            stats: Stats::default(),
+            hints: Hints::default(),
+            hub: None,
+            push_until: 0,
+        }
+    }
 
+    pub async fn check(&mut self, http: &reqwest::Client) -> Result<Vec<Change>, Error> {
+        let http = self.options.client(http).map_err(Error::OptionsError)?;
+        let mut request = self.options.apply(http.get(&self.url));
+        if let Some(credentials) = self.credentials.as_ref() {
+            request = credentials.apply(request);
+        }
+        // Only the server's own validators are sent back, as our clock may not agree with its.
         if let Some(etag) = self.etag.as_ref() {
             request = request.header(header::IF_NONE_MATCH, etag);
         }
+        if let Some(last_modified) = self.last_modified.as_ref() {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
 
-        let resp = request.send().await?.error_for_status()?;
-        let expiry = find_expiry(resp.headers());
-        let entries = if resp.status().as_u16() == StatusCode::NOT_MODIFIED {
+        let resp = send(request).await?;
+        let freshness = CachePolicy::parse(resp.headers()).freshness();
+        let not_modified = resp.status().as_u16() == StatusCode::NOT_MODIFIED;
+        let etag = validator(resp.headers(), header::ETAG);
+        let last_modified = validator(resp.headers(), header::LAST_MODIFIED);
+        let entries = if not_modified {
             Vec::new()
         } else {
             let xml = resp.bytes().await?;
-            let mut feed = feed_rs::parser::parse(xml.as_ref())?;
-            feed.entries
-                .retain(|entry| !self.seen_entries.contains(&entry.id));
-            feed.entries
+            let feed = feed_rs::parser::parse(xml.as_ref())?;
+            self.hints = Hints::parse(xml.as_ref(), &feed);
+            self.hub = Hub::find(&feed, &self.url);
+            self.find_changes(feed.entries, false)
         };
 
+        // Only replaced once the content was parsed, or a broken response would be skipped over
+        // by the next conditional request. A "304 Not Modified" may leave the validators out.
+        if !not_modified || etag.is_some() {
+            self.etag = etag;
+        }
+        if !not_modified || last_modified.is_some() {
+            self.last_modified = last_modified;
+        }
         self.last_fetch = Utc::now();
-        match expiry {
-            Ok(expiry) => self.next_fetch = expiry,
-            Err(_) => self.reset_expiry(),
-        };
+        self.stats.record_poll(not_modified);
+        self.stats.record_entries(
+            entries.iter().filter(|change| !change.updated).count(),
+            self.last_fetch.timestamp(),
+        );
+        self.schedule(freshness);
         Ok(entries)
     }
 
-    pub fn reset_entries(&mut self, entries: &[feed_rs::model::Entry]) {
-        let clear_entries = entries
-            .iter()
-            .map(|entry| &entry.id)
-            .collect::<HashSet<_>>();
-        self.last_fetch = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp_opt(0, 0).unwrap(), Utc);
+    /// Process content pushed by a WebSub hub, which may only contain the new entries.
+    pub fn ingest(&mut self, xml: &[u8]) -> Result<Vec<Change>, Error> {
+        let feed = feed_rs::parser::parse(xml)?;
+        let entries = self.find_changes(feed.entries, true);
+        self.stats.record_entries(
+            entries.iter().filter(|change| !change.updated).count(),
+            Utc::now().timestamp(),
+        );
+        Ok(entries)
+    }
+
+    /// Compare the entries against those already seen, remembering the new fingerprints.
+    ///
+    /// Entries are identified by their ID. If the IDs are all unknown, but the link or content hash
+    /// matches an entry we've seen before, it's considered to be the same entry. This covers feeds
+    /// without IDs (where the parser makes one up) or feeds that change IDs on every fetch.
+    ///
+    /// A `partial` update, like a push, only adds to the entries present in the feed, rather than
+    /// replacing them, so that those left out are not taken for removed ones and pruned.
+    fn find_changes(&mut self, entries: Vec<feed_rs::model::Entry>, partial: bool) -> Vec<Change> {
+        // Only fall back if none of the IDs are known, or we would risk merging entries that
+        // happen to share a link (like feeds where everything links to the home page). A push
+        // usually has nothing but new entries, so it tells nothing about churn.
+        let ids_churned = !partial
+            && !self.seen_entries.is_empty()
+            && !entries
+                .iter()
+                .any(|entry| self.seen_entries.contains_key(&entry.id));
+
+        let mut by_link = HashMap::new();
+        let mut by_hash = HashMap::new();
+        if ids_churned {
+            let mut dup_links = HashSet::new();
+            for (id, fingerprint) in self.seen_entries.iter() {
+                if let Some(link) = fingerprint.link.as_ref() {
+                    if by_link.insert(link.clone(), id.clone()).is_some() {
+                        dup_links.insert(link.clone());
+                    }
+                }
+                if let Some(hash) = fingerprint.hash.as_ref() {
+                    by_hash.insert(hash.clone(), id.clone());
+                }
+            }
+            by_link.retain(|link, _| !dup_links.contains(link));
+        }
+
+        if !partial {
+            self.present_entries.clear();
+        }
+        self.renamed_entries.clear();
+        self.present_entries
+            .extend(entries.iter().map(|entry| entry.id.clone()));
+
+        let mut changes = Vec::new();
+        for entry in entries {
+            let fingerprint = Fingerprint::of(&entry);
+            let known_id = if self.seen_entries.contains_key(&entry.id) {
+                Some(entry.id.clone())
+            } else {
+                fingerprint
+                    .link
+                    .as_ref()
+                    .and_then(|link| by_link.get(link))
+                    .or_else(|| fingerprint.hash.as_ref().and_then(|h| by_hash.get(h)))
+                    .cloned()
+            };
+
+            match known_id.and_then(|id| Some((self.seen_entries.remove(&id)?, id))) {
+                Some((old, old_id)) => {
+                    if old_id != entry.id {
+                        self.renamed_entries.push((old_id, entry.id.clone()));
+                    }
+                    let updated = !old.is_legacy() && old != fingerprint;
+                    self.seen_entries.insert(entry.id.clone(), fingerprint);
+                    if updated {
+                        changes.push(Change {
+                            entry,
+                            updated: true,
+                            previous: Some(old),
+                        });
+                    }
+                }
+                None => {
+                    self.seen_entries.insert(entry.id.clone(), fingerprint);
+                    changes.push(Change {
+                        entry,
+                        updated: false,
+                        previous: None,
+                    });
+                }
+            }
+        }
+        changes
+    }
+
+    /// Forget the changes, so that the next check finds them again after they couldn't be delivered.
+    ///
+    /// New entries are forgotten altogether, while edited ones go back to what they were before, or
+    /// they would come back as new entries.
+    pub fn reset_entries(&mut self, changes: &[Change]) {
+        self.last_fetch =
+            DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp_opt(0, 0).unwrap(), Utc);
         self.etag = None;
-        self.seen_entries
-            .retain(|entry| !clear_entries.contains(&entry));
+        self.last_modified = None;
+        for change in changes {
+            match change.previous.as_ref() {
+                Some(previous) => {
+                    self.seen_entries
+                        .insert(change.entry.id.clone(), previous.clone());
+                }
+                None => {
+                    self.seen_entries.remove(&change.entry.id);
+                    self.present_entries.remove(&change.entry.id);
+                }
+            }
+        }
+    }
+
+    /// Drop the subscribers and entries, which can be loaded again from the database when needed.
+    pub fn unload(&mut self) {
+        self.users = Vec::new();
+        self.seen_entries = HashMap::new();
+        self.present_entries = HashSet::new();
+    }
+
+    /// Figure out when the feed should be checked next, given the server's cache hint (if any).
+    ///
+    /// The feed's own posting history is preferred over the fixed default, but the feed is never
+    /// fetched sooner than the server or the feed's `<ttl>` allow, nor often if updates are being
+    /// pushed. An `/interval` replaces all of that, and `ignore_cache` drops the server's hint. The
+    /// result is then pushed past any hours or days the feed asked to be skipped. Whichever of these
+    /// had the last word is remembered in `scheduled_by`.
+    fn schedule(&mut self, server: Option<(Duration, cache::Source)>) {
+        // Can't use constants here, `Duration::seconds` is not a const-fn as of 0.4.19.
+        let max_fetch_delay: Duration = Duration::seconds(MAX_FETCH_DELAY);
+        let min_fetch_delay: Duration = Duration::seconds(MIN_FETCH_DELAY);
+
+        // If the server does not have any max age or expiration for the feed, use a default delay.
+        let default_fetch_delay: Duration = Duration::seconds(10 * 60);
+
+        // If a WebSub hub is pushing the updates, only poll occasionally in case the hub breaks.
+        let push_fetch_delay: Duration = Duration::seconds(6 * 60 * 60);
+
+        let server = server.filter(|_| !self.ignore_cache);
+        let (mut delay, mut source) = if let Some(interval) = self.interval {
+            // Chosen by hand, so the hints are not second-guessed.
+            (Duration::seconds(interval), ScheduleSource::Interval)
+        } else if let Some(delay) = self.stats.adaptive_delay(default_fetch_delay) {
+            (delay, ScheduleSource::History)
+        } else if let Some(period) = self.hints.update_period {
+            (Duration::seconds(period), ScheduleSource::UpdatePeriod)
+        } else if let Some((delay, header)) = server {
+            (delay, ScheduleSource::Server(header))
+        } else {
+            (default_fetch_delay, ScheduleSource::Default)
+        };
+
+        let mut floors = vec![(min_fetch_delay, ScheduleSource::Minimum)];
+        if self.interval.is_none() {
+            if let Some((server_delay, header)) = server {
+                floors.push((server_delay, ScheduleSource::Server(header)));
+            }
+            if let Some(ttl) = self.hints.ttl {
+                floors.push((Duration::seconds(ttl), ScheduleSource::Ttl));
+            }
+            // Polling is only a fallback while a hub is pushing updates to us.
+            if self.push_until > Utc::now().timestamp() {
+                floors.push((push_fetch_delay, ScheduleSource::Push));
+            }
+        }
+        for (floor, why) in floors {
+            if floor > delay {
+                delay = floor;
+                source = why;
+            }
+        }
+        if delay > max_fetch_delay {
+            delay = max_fetch_delay;
+            source = ScheduleSource::Maximum;
+        }
+
+        // Skipping is done one hour at a time, and a week covers every possible combination.
+        let now = Utc::now();
+        for _ in 0..7 * 24 {
+            if !self.hints.skips(now + delay) {
+                break;
+            }
+            delay = delay + Duration::hours(1);
+            source = ScheduleSource::Skip;
+        }
+
+        self.scheduled_by = source;
+        // Can't panic, the minimum among the floors made it positive, so `to_std()` succeeds.
+        self.next_fetch = Instant::now() + delay.to_std().unwrap();
+    }
+
+    /// Change the polling overrides, checking sooner if the new interval asks for it.
+    ///
+    /// Anything longer only takes effect after the next check, which is when the feed is scheduled.
+    pub fn set_overrides(&mut self, interval: Option<i64>, ignore_cache: bool) {
+        self.interval = interval;
+        self.ignore_cache = ignore_cache;
+        if let Some(interval) = interval {
+            let next_fetch = Instant::now() + std::time::Duration::from_secs(interval as u64);
+            if next_fetch < self.next_fetch {
+                self.next_fetch = next_fetch;
+                self.scheduled_by = ScheduleSource::Interval;
+            }
+        }
+    }
+
+    /// Make the feed due right away, regardless of its schedule.
+    pub fn check_now(&mut self) {
+        self.next_fetch = Instant::now();
+        self.scheduled_by = ScheduleSource::Forced;
     }
 
     pub fn reset_expiry(&mut self) {
         self.next_fetch = Instant::now() + Duration::seconds(10 * 60).to_std().unwrap();
+        self.scheduled_by = ScheduleSource::Retry;
     }
 
     pub fn next_fetch_timestamp(&self) -> i64 {
@@ -169,9 +723,73 @@ impl Feed {
     }
 }
 
+/// The instant at the Unix timestamp, or now if it already passed. The opposite of
+/// `Feed::next_fetch_timestamp`.
+pub fn instant_at(timestamp: i64) -> Instant {
+    let delta = timestamp.saturating_sub(Utc::now().timestamp());
+    let now = Instant::now();
+    if delta < 0 {
+        now
+    } else {
+        // Too far ahead to be represented means it's wrong, so wait as long as a feed ever would.
+        now.checked_add(std::time::Duration::from_secs(delta as u64))
+            .unwrap_or_else(|| now + std::time::Duration::from_secs(MAX_FETCH_DELAY as u64))
+    }
+}
+
+/// Client for the thumbnails, which doesn't follow redirects, as they could lead anywhere.
+static THUMBNAIL_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(std::time::Duration::from_secs(30))
+        .build()
+        .expect("failed to build the thumbnail client")
+});
+
+/// Telegram won't take photos larger than this anyway.
+const MAX_THUMBNAIL_SIZE: usize = 5 * 1024 * 1024;
+
+/// Download the first thumbnail of the entry, if it has any, it's on a public address and it's not
+/// too large. Only the tests let it be on this machine, with `allow_loopback`.
+pub async fn fetch_thumbnail(
+    entry: &feed_rs::model::Entry,
+    allow_loopback: bool,
+) -> Option<Vec<u8>> {
+    let url = entry
+        .media
+        .iter()
+        .flat_map(|media| media.thumbnails.iter())
+        .map(|thumbnail| thumbnail.image.uri.as_str())
+        .next()?;
+
+    // Whoever writes the feed picks the URL, which mustn't be a way to reach this machine.
+    if !net::resolves_publicly(url, allow_loopback).await {
+        return None;
+    }
+    let mut resp = THUMBNAIL_CLIENT
+        .get(url)
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+    if resp.content_length().unwrap_or(0) as usize > MAX_THUMBNAIL_SIZE {
+        return None;
+    }
+    // The length may be missing or wrong, so stop reading as soon as there's too much.
+    let mut bytes = Vec::new();
+    while let Some(chunk) = resp.chunk().await.ok()? {
+        if bytes.len() + chunk.len() > MAX_THUMBNAIL_SIZE {
+            return None;
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Some(bytes)
+}
+
 impl PartialEq for Feed {
     fn eq(&self, other: &Self) -> bool {
-        self.next_fetch == other.next_fetch && self.url == other.url
+        self.next_fetch == other.next_fetch && self.url == other.url && self.id == other.id
     }
 }
 
@@ -188,6 +806,7 @@ impl Ord for Feed {
         self.next_fetch
             .cmp(&other.next_fetch)
             .then_with(|| self.url.cmp(&other.url))
+            .then_with(|| self.id.cmp(&other.id))
     }
 }
 
@@ -203,12 +822,23 @@ impl From<feed_rs::parser::ParseFeedError> for Error {
     }
 }
 
+impl Error {
+    /// Short name of the variant, to group errors by.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::ReadError(_) => "network",
+            Self::ParseError(_) => "parse",
+            Self::OptionsError(_) => "options",
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::ReadError(e) => write!(f, "network error: {}", e),
             Self::ParseError(e) => write!(f, "error parsing feed: {}", e),
-            Self::MalformedHeader(e) => write!(f, "error parsing header {}", e),
+            Self::OptionsError(e) => write!(f, "bad request options: {}", e),
         }
     }
 }
@@ -218,6 +848,10 @@ impl std::error::Error for Error {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
 
     // Fetch an old feed and then its updated variant to figure out how "new entries" works.
     static OLD_FEED: &str = env!("OLD_FEED");
@@ -231,7 +865,11 @@ mod tests {
             let mut feed = Feed::new(
                 &http,
                 OLD_FEED,
-                PackedChat::from_bytes(&[2, 6, 0, 0, 0, 0]).unwrap(),
+                None,
+                Target::Telegram(
+                    grammers_client::types::chat::PackedChat::from_bytes(&[2, 6, 0, 0, 0, 0])
+                        .unwrap(),
+                ),
             )
             .await?;
             feed.url = NEW_FEED.to_string();
@@ -239,4 +877,494 @@ mod tests {
             Ok(())
         })
     }
+
+    type Requests = Arc<Mutex<Vec<(Option<String>, Option<String>)>>>;
+
+    fn validators(version: usize) -> (String, String) {
+        (
+            format!("W/\"v{}\"", version),
+            format!(
+                "{}, 0{} May 2021 00:00:00 GMT",
+                ["Sat", "Sun"][version - 1],
+                version
+            ),
+        )
+    }
+
+    /// Server for a feed with `version` entries, which honours conditional requests only if they
+    /// echo its validators exactly. Records the `If-None-Match` and `If-Modified-Since` received.
+    fn serve(version: Arc<Mutex<usize>>, requests: Requests) -> String {
+        let make_svc = make_service_fn(move |_| {
+            let version = version.clone();
+            let requests = requests.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let version = *version.lock().unwrap();
+                    let get = |key: header::HeaderName| {
+                        req.headers()
+                            .get(key)
+                            .map(|v| v.to_str().unwrap().to_string())
+                    };
+                    let (if_none_match, if_modified_since) =
+                        (get(header::IF_NONE_MATCH), get(header::IF_MODIFIED_SINCE));
+                    requests
+                        .lock()
+                        .unwrap()
+                        .push((if_none_match.clone(), if_modified_since.clone()));
+
+                    let (etag, last_modified) = validators(version);
+                    let fresh = match (if_none_match, if_modified_since) {
+                        (Some(tag), _) => tag == etag,
+                        (None, Some(date)) => date == last_modified,
+                        (None, None) => false,
+                    };
+                    let resp = if fresh {
+                        Response::builder().status(304).body(Body::empty())
+                    } else {
+                        let mut xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom"><title>Test</title><id>urn:test</id>"#
+                            .to_string();
+                        for i in 1..=version {
+                            xml.push_str(&format!(
+                                "<entry><title>{0}</title><id>urn:test:{0}</id></entry>",
+                                i
+                            ));
+                        }
+                        xml.push_str("</feed>");
+                        Response::builder()
+                            .header(header::ETAG, etag)
+                            .header(header::LAST_MODIFIED, last_modified)
+                            .body(Body::from(xml))
+                    };
+                    async move { Ok::<_, Infallible>(resp.unwrap()) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/atom.xml", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    /// An Atom feed with entries made of an ID, a title and a link.
+    fn atom(entries: &[(&str, &str, &str)]) -> String {
+        let mut xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom"><title>Test</title><id>urn:test</id>"#
+            .to_string();
+        for (id, title, link) in entries {
+            xml.push_str(&format!(
+                "<entry><title>{}</title><id>{}</id><link href=\"{}\"/></entry>",
+                title, id, link
+            ));
+        }
+        xml.push_str("</feed>");
+        xml
+    }
+
+    fn entries(entries: &[(&str, &str, &str)]) -> Vec<feed_rs::model::Entry> {
+        feed_rs::parser::parse(atom(entries).as_bytes())
+            .unwrap()
+            .entries
+    }
+
+    fn present(feed: &Feed) -> Vec<&str> {
+        let mut present = feed
+            .present_entries
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        present.sort_unstable();
+        present
+    }
+
+    fn seen(feed: &Feed) -> Vec<&str> {
+        let mut seen = feed
+            .seen_entries
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        seen.sort_unstable();
+        seen
+    }
+
+    #[test]
+    fn check_churned_ids_matched_by_link() {
+        let mut feed = Feed::for_test("https://example.com/atom.xml");
+        feed.find_changes(
+            entries(&[
+                ("1", "One", "https://example.com/1"),
+                ("2", "Two", "https://example.com/2"),
+            ]),
+            false,
+        );
+
+        let changes = feed.find_changes(
+            entries(&[
+                ("a", "One", "https://example.com/1"),
+                ("b", "Two, edited", "https://example.com/2"),
+            ]),
+            false,
+        );
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].updated);
+        assert_eq!(changes[0].entry.id, "b");
+        assert_eq!(seen(&feed), vec!["a", "b"]);
+        let mut renamed = feed.renamed_entries.clone();
+        renamed.sort_unstable();
+        assert_eq!(
+            renamed,
+            vec![
+                ("1".to_string(), "a".to_string()),
+                ("2".to_string(), "b".to_string()),
+            ]
+        );
+
+        // Nothing is renamed once the IDs stay put.
+        assert!(feed
+            .find_changes(
+                entries(&[
+                    ("a", "One", "https://example.com/1"),
+                    ("b", "Two, edited", "https://example.com/2"),
+                ]),
+                false,
+            )
+            .is_empty());
+        assert!(feed.renamed_entries.is_empty());
+    }
+
+    #[test]
+    fn check_duplicate_links_fall_back_to_hash() {
+        let mut feed = Feed::for_test("https://example.com/atom.xml");
+        feed.find_changes(
+            entries(&[
+                ("1", "One", "https://example.com/"),
+                ("2", "Two", "https://example.com/"),
+            ]),
+            false,
+        );
+
+        // The link can't tell the entries apart, but their content still can.
+        let changes = feed.find_changes(
+            entries(&[
+                ("a", "One", "https://example.com/"),
+                ("b", "Two", "https://example.com/"),
+                ("c", "Three", "https://example.com/"),
+            ]),
+            false,
+        );
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].updated);
+        assert_eq!(changes[0].entry.id, "c");
+        assert_eq!(seen(&feed), vec!["a", "b", "c"]);
+        assert_eq!(feed.renamed_entries.len(), 2);
+    }
+
+    #[test]
+    fn check_reset_changes_found_again() {
+        let mut feed = Feed::for_test("https://example.com/atom.xml");
+        feed.find_changes(
+            entries(&[
+                ("1", "One", "https://example.com/1"),
+                ("2", "Two", "https://example.com/2"),
+            ]),
+            false,
+        );
+
+        let fetched = [
+            ("1", "One, edited", "https://example.com/1"),
+            ("2", "Two", "https://example.com/2"),
+            ("3", "Three", "https://example.com/3"),
+        ];
+        let changes = feed.find_changes(entries(&fetched), false);
+        assert_eq!(changes.len(), 2);
+        feed.reset_entries(&changes);
+        assert_eq!(seen(&feed), vec!["1", "2"]);
+
+        // The edit is still an edit, rather than a new entry.
+        let changes = feed.find_changes(entries(&fetched), false);
+        let found = changes
+            .iter()
+            .map(|change| (change.entry.id.as_str(), change.updated))
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![("1", true), ("3", false)]);
+    }
+
+    #[test]
+    fn check_pushes_add_to_present_entries() {
+        let mut feed = Feed::for_test("https://example.com/atom.xml");
+        feed.find_changes(
+            entries(&[
+                ("1", "One", "https://example.com/1"),
+                ("2", "Two", "https://example.com/2"),
+            ]),
+            false,
+        );
+
+        let pushed = atom(&[("3", "Three", "https://example.com/3")]);
+        let changes = feed.ingest(pushed.as_bytes()).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].updated);
+        assert_eq!(present(&feed), vec!["1", "2", "3"]);
+
+        // Only a fetch tells which entries were removed.
+        feed.find_changes(entries(&[("3", "Three", "https://example.com/3")]), false);
+        assert_eq!(present(&feed), vec!["3"]);
+    }
+
+    /// Serve a small thumbnail, one too large to say how large it is, and a redirect to the first.
+    fn serve_thumbnails() -> String {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| {
+                let resp = match req.uri().path() {
+                    "/small.jpg" => Response::new(Body::from(vec![0u8; 16])),
+                    "/large.jpg" => {
+                        let (mut tx, body) = Body::channel();
+                        tokio::spawn(async move {
+                            for _ in 0..=MAX_THUMBNAIL_SIZE / (1024 * 1024) {
+                                if tx.send_data(vec![0u8; 1024 * 1024].into()).await.is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                        Response::new(body)
+                    }
+                    _ => Response::builder()
+                        .status(302)
+                        .header(header::LOCATION, "/small.jpg")
+                        .body(Body::empty())
+                        .unwrap(),
+                };
+                async move { Ok::<_, Infallible>(resp) }
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    fn with_thumbnail(url: &str) -> feed_rs::model::Entry {
+        let xml = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0" xmlns:media="http://search.yahoo.com/mrss/"><channel><title>Test</title>
+<item><guid>1</guid><title>One</title><media:thumbnail url="{}"/></item></channel></rss>"#,
+            url
+        );
+        feed_rs::parser::parse(xml.as_bytes())
+            .unwrap()
+            .entries
+            .remove(0)
+    }
+
+    #[tokio::test]
+    async fn check_thumbnails_limited() {
+        let base = serve_thumbnails();
+        let small = fetch_thumbnail(&with_thumbnail(&format!("{}/small.jpg", base)), true).await;
+        assert_eq!(small.map(|bytes| bytes.len()), Some(16));
+        assert_eq!(
+            fetch_thumbnail(&with_thumbnail(&format!("{}/large.jpg", base)), true).await,
+            None
+        );
+        assert_eq!(
+            fetch_thumbnail(&with_thumbnail(&format!("{}/moved.jpg", base)), true).await,
+            None
+        );
+        assert_eq!(
+            fetch_thumbnail(&with_thumbnail("http://10.0.0.1/small.jpg"), true).await,
+            None
+        );
+        assert_eq!(
+            fetch_thumbnail(&with_thumbnail(&format!("{}/small.jpg", base)), false).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn check_validators_echoed_verbatim() {
+        let version = Arc::new(Mutex::new(1));
+        let requests = Requests::default();
+        let url = serve(version.clone(), requests.clone());
+        let http = reqwest::Client::new();
+        let (etag1, date1) = validators(1);
+        let (etag2, date2) = validators(2);
+
+        let mut feed = Feed::fetch(&http, &url, None).await.unwrap();
+        assert_eq!(feed.etag.as_ref(), Some(&etag1));
+        assert_eq!(feed.last_modified.as_ref(), Some(&date1));
+
+        // Answered with a bare 304, which must not make us forget the validators.
+        assert!(feed.check(&http).await.unwrap().is_empty());
+        assert_eq!(feed.stats.not_modified, 1);
+        assert_eq!(feed.etag.as_ref(), Some(&etag1));
+
+        *version.lock().unwrap() = 2;
+        assert_eq!(feed.check(&http).await.unwrap().len(), 1);
+        assert_eq!(feed.etag.as_ref(), Some(&etag2));
+        assert_eq!(feed.last_modified.as_ref(), Some(&date2));
+
+        // Without an ETag, the server's own date is what's sent back.
+        feed.etag = None;
+        assert!(feed.check(&http).await.unwrap().is_empty());
+        assert_eq!(feed.stats.not_modified, 2);
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                (None, None),
+                (Some(etag1.clone()), Some(date1.clone())),
+                (Some(etag1), Some(date1)),
+                (None, Some(date2)),
+            ]
+        );
+    }
+
+    /// Seconds until the feed is next fetched.
+    fn delay(feed: &Feed) -> i64 {
+        feed.next_fetch
+            .saturating_duration_since(Instant::now())
+            .as_secs_f64()
+            .round() as i64
+    }
+
+    #[test]
+    fn check_poll_counters_decay() {
+        let mut stats = Stats::default();
+        for _ in 0..999 {
+            stats.record_poll(false);
+        }
+        stats.record_poll(true);
+        assert_eq!((stats.polls, stats.not_modified), (1000, 1));
+
+        // Past that, the old polls weigh half as much.
+        stats.record_poll(true);
+        assert_eq!((stats.polls, stats.not_modified), (501, 1));
+    }
+
+    #[test]
+    fn check_adaptive_delay() {
+        let default = Duration::seconds(600);
+        let mut stats = Stats::default();
+        assert_eq!(stats.adaptive_delay(default), None);
+
+        // Without knowing how often it posts, it's polled less the less it changes.
+        stats.polls = 10;
+        assert_eq!(stats.adaptive_delay(default), Some(Duration::seconds(600)));
+        stats.not_modified = 5;
+        assert_eq!(stats.adaptive_delay(default), Some(Duration::seconds(1500)));
+        stats.not_modified = 10;
+        assert_eq!(stats.adaptive_delay(default), Some(Duration::seconds(2400)));
+
+        // Knowing it, several times between entries.
+        stats.entry_interval = Some(3600);
+        assert_eq!(
+            stats.adaptive_delay(default),
+            Some(Duration::seconds(3600 / POLLS_PER_ENTRY))
+        );
+    }
+
+    #[test]
+    fn check_schedule_backs_off_and_speeds_up() {
+        let mut feed = Feed::for_test("https://example.com/atom.xml");
+        feed.schedule(None);
+        assert_eq!(feed.scheduled_by, ScheduleSource::Default);
+        assert_eq!(delay(&feed), 600);
+
+        // Feeds that never change are polled less.
+        feed.stats.polls = 20;
+        feed.stats.not_modified = 20;
+        feed.schedule(None);
+        assert_eq!(feed.scheduled_by, ScheduleSource::History);
+        assert_eq!(delay(&feed), 2400);
+
+        // Feeds that post often are polled more, but not more than the minimum.
+        feed.stats.entry_interval = Some(400);
+        feed.schedule(None);
+        assert_eq!(feed.scheduled_by, ScheduleSource::History);
+        assert_eq!(delay(&feed), 100);
+        feed.stats.entry_interval = Some(40);
+        feed.schedule(None);
+        assert_eq!(feed.scheduled_by, ScheduleSource::Minimum);
+        assert_eq!(delay(&feed), MIN_FETCH_DELAY);
+
+        // And those that hardly post, not less than the maximum.
+        feed.stats.entry_interval = Some(30 * 24 * 60 * 60);
+        feed.schedule(None);
+        assert_eq!(feed.scheduled_by, ScheduleSource::Maximum);
+        assert_eq!(delay(&feed), MAX_FETCH_DELAY);
+
+        // The server and the feed itself may ask for a slower pace, unless told otherwise.
+        let server = Some((Duration::seconds(3600), cache::Source::MaxAge));
+        feed.stats.entry_interval = Some(400);
+        feed.schedule(server);
+        assert_eq!(
+            feed.scheduled_by,
+            ScheduleSource::Server(cache::Source::MaxAge)
+        );
+        assert_eq!(delay(&feed), 3600);
+        feed.hints.ttl = Some(7200);
+        feed.schedule(server);
+        assert_eq!(feed.scheduled_by, ScheduleSource::Ttl);
+        assert_eq!(delay(&feed), 7200);
+        feed.hints.ttl = None;
+        feed.ignore_cache = true;
+        feed.schedule(server);
+        assert_eq!(feed.scheduled_by, ScheduleSource::History);
+        assert_eq!(delay(&feed), 100);
+
+        // An interval set by hand wins over everything but the minimum.
+        feed.interval = Some(300);
+        feed.schedule(server);
+        assert_eq!(feed.scheduled_by, ScheduleSource::Interval);
+        assert_eq!(delay(&feed), 300);
+    }
+
+    #[tokio::test]
+    async fn check_cache_headers_schedule_next_fetch() {
+        let cache_control = Arc::new(Mutex::new(Some("max-age=3600")));
+        let make_svc = make_service_fn({
+            let cache_control = cache_control.clone();
+            move |_| {
+                let cache_control = cache_control.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |_| {
+                        let mut resp = Response::builder();
+                        if let Some(value) = *cache_control.lock().unwrap() {
+                            resp = resp.header(header::CACHE_CONTROL, value);
+                        }
+                        let resp =
+                            resp.body(Body::from(atom(&[("1", "Entry", "https://example.com/1")])));
+                        async move { Ok::<_, Infallible>(resp.unwrap()) }
+                    }))
+                }
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/atom.xml", server.local_addr());
+        tokio::spawn(server);
+        let http = reqwest::Client::new();
+
+        let mut feed = Feed::fetch(&http, &url, None).await.unwrap();
+        assert_eq!(
+            feed.scheduled_by,
+            ScheduleSource::Server(cache::Source::MaxAge)
+        );
+        assert_eq!(delay(&feed), 3600);
+
+        // Each response decides anew, so without the header it's back to the default.
+        *cache_control.lock().unwrap() = None;
+        feed.check(&http).await.unwrap();
+        assert_eq!(feed.scheduled_by, ScheduleSource::Default);
+        assert_eq!(delay(&feed), 600);
+
+        // Asking for less than the minimum doesn't get it, and ignoring the cache drops the header.
+        *cache_control.lock().unwrap() = Some("max-age=5");
+        feed.check(&http).await.unwrap();
+        assert_eq!(feed.scheduled_by, ScheduleSource::Minimum);
+        assert_eq!(delay(&feed), MIN_FETCH_DELAY);
+        *cache_control.lock().unwrap() = Some("max-age=3600");
+        feed.ignore_cache = true;
+        feed.check(&http).await.unwrap();
+        assert_eq!(feed.scheduled_by, ScheduleSource::Default);
+        assert_eq!(delay(&feed), 600);
+    }
 }
@@ -0,0 +1,202 @@
+use crate::scheduler::Scheduler;
+use crate::storage::Storage;
+use chrono::Utc;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A feed cycle taking longer than this many seconds is assumed to be stuck, and so is the loop
+/// if a feed has been due for longer than this without a cycle starting.
+const MAX_CYCLE_SECONDS: i64 = 30 * 60;
+
+static HEALTH: Health = Health::new();
+
+/// Liveness of the long-running tasks, as reported by `/healthz`.
+///
+/// Timestamps are zero when whatever they refer to hasn't happened yet.
+pub struct Health {
+    updates_alive: AtomicBool,
+    last_update: AtomicI64,
+    cycle_started: AtomicI64,
+    last_cycle: AtomicI64,
+}
+
+/// Marks `handle_updates` as alive until dropped.
+pub struct UpdatesAlive;
+
+pub fn health() -> &'static Health {
+    &HEALTH
+}
+
+impl Health {
+    const fn new() -> Self {
+        Self {
+            updates_alive: AtomicBool::new(false),
+            last_update: AtomicI64::new(0),
+            cycle_started: AtomicI64::new(0),
+            last_cycle: AtomicI64::new(0),
+        }
+    }
+
+    pub fn updates_alive(&self) -> UpdatesAlive {
+        self.updates_alive.store(true, Ordering::Relaxed);
+        UpdatesAlive
+    }
+
+    pub fn update_received(&self) {
+        self.last_update
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    pub fn cycle_started(&self) {
+        self.cycle_started
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    pub fn cycle_finished(&self) {
+        self.cycle_started.store(0, Ordering::Relaxed);
+        self.last_cycle
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    /// Whether everything is working, along with the details as JSON.
+    ///
+    /// The last cycle having finished long ago is only wrong if some feed was due meanwhile, as
+    /// there's nothing to do until then. `overdue` is how long the feed due first has been waiting.
+    pub async fn report(&self, db: &dyn Storage, overdue: Duration) -> (bool, String) {
+        let now = Utc::now().timestamp();
+        let updates_alive = self.updates_alive.load(Ordering::Relaxed);
+        let cycle_started = self.cycle_started.load(Ordering::Relaxed);
+        let overdue = overdue.as_secs() as i64;
+        let feeds_ok = if cycle_started == 0 {
+            overdue <= MAX_CYCLE_SECONDS
+        } else {
+            now - cycle_started <= MAX_CYCLE_SECONDS
+        };
+        let writable = db.check_writable().await;
+
+        let json = serde_json::json!({
+            "updates": {
+                "alive": updates_alive,
+                "last_update": self.last_update.load(Ordering::Relaxed),
+            },
+            "feeds": {
+                "ok": feeds_ok,
+                "cycle_started": cycle_started,
+                "last_cycle": self.last_cycle.load(Ordering::Relaxed),
+                "overdue": overdue,
+            },
+            "database": {
+                "writable": writable.is_ok(),
+                "error": writable.err().map(|e| e.to_string()),
+            },
+        });
+        let ok = updates_alive && feeds_ok && json["database"]["writable"] == true;
+        (ok, json.to_string())
+    }
+}
+
+impl Drop for UpdatesAlive {
+    fn drop(&mut self) {
+        HEALTH.updates_alive.store(false, Ordering::Relaxed);
+    }
+}
+
+/// The answer to `/healthz`, which is `503 Service Unavailable` if something is wrong.
+pub async fn response(db: &dyn Storage, scheduler: &Scheduler) -> Response<Body> {
+    let (ok, report) = health().report(db, scheduler.overdue()).await;
+    let mut response = Response::new(Body::from(report));
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+    if !ok {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    response
+}
+
+struct State {
+    db: Arc<dyn Storage>,
+    scheduler: Arc<Scheduler>,
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    Ok(match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => response(state.db.as_ref(), &state.scheduler).await,
+        _ => {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            response
+        }
+    })
+}
+
+/// Serve only the health check at `/healthz` on `addr`, so that it can be reached without
+/// exposing the metrics.
+pub async fn run(
+    addr: SocketAddr,
+    db: Arc<dyn Storage>,
+    scheduler: Arc<Scheduler>,
+) -> Result<(), hyper::Error> {
+    let state = Arc::new(State { db, scheduler });
+    let make_svc = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+    });
+    Server::bind(&addr).serve(make_svc).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn check_health_report() {
+        let db = crate::db::Database::new(":memory:").unwrap();
+        let health = Health::new();
+        assert!(!health.report(&db, Duration::from_secs(0)).await.0);
+
+        health.updates_alive.store(true, Ordering::Relaxed);
+        health.cycle_started();
+        assert!(health.report(&db, Duration::from_secs(0)).await.0);
+
+        // A cycle that never finishes means `handle_feed` is wedged.
+        health.cycle_started.store(
+            Utc::now().timestamp() - MAX_CYCLE_SECONDS - 1,
+            Ordering::Relaxed,
+        );
+        let (ok, json) = health.report(&db, Duration::from_secs(0)).await;
+        assert!(!ok);
+        let json = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+        assert_eq!(json["feeds"]["ok"], false);
+        assert_eq!(json["database"]["writable"], true);
+
+        health.cycle_finished();
+        assert!(health.report(&db, Duration::from_secs(0)).await.0);
+    }
+
+    #[tokio::test]
+    async fn check_overdue_feeds_reported() {
+        let db = crate::db::Database::new(":memory:").unwrap();
+        let health = Health::new();
+        health.updates_alive.store(true, Ordering::Relaxed);
+        health.cycle_finished();
+        let limit = MAX_CYCLE_SECONDS as u64;
+
+        // No cycle has started in a while, which is fine unless a feed was due meanwhile.
+        assert!(health.report(&db, Duration::from_secs(limit)).await.0);
+        let (ok, json) = health.report(&db, Duration::from_secs(limit + 1)).await;
+        assert!(!ok);
+        let json = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+        assert_eq!(json["feeds"]["ok"], false);
+        assert_eq!(json["feeds"]["overdue"], limit + 1);
+
+        // While a cycle runs, feeds are due until it's done, which is checked on its own.
+        health.cycle_started();
+        assert!(health.report(&db, Duration::from_secs(limit + 1)).await.0);
+    }
+}
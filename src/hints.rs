@@ -0,0 +1,144 @@
+use chrono::{DateTime, Datelike, Timelike, Utc};
+
+/// Polling hints declared by the feed itself, as opposed to the HTTP caching headers.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hints {
+    /// RSS `<ttl>`, in seconds. The feed should not be fetched more often than this.
+    pub ttl: Option<i64>,
+    /// Syndication module `sy:updatePeriod` divided by `sy:updateFrequency`, in seconds.
+    pub update_period: Option<i64>,
+    /// RSS `<skipHours>` as a bitmask, where bit 0 is midnight GMT.
+    pub skip_hours: u32,
+    /// RSS `<skipDays>` as a bitmask, where bit 0 is Monday.
+    pub skip_days: u32,
+}
+
+static DAYS: [&str; 7] = [
+    "monday",
+    "tuesday",
+    "wednesday",
+    "thursday",
+    "friday",
+    "saturday",
+    "sunday",
+];
+
+/// Find the text inside every `<tag>...</tag>` (attributes are allowed in the opening tag).
+///
+/// This is not a real XML parser, but `feed_rs` doesn't expose these elements, and they are
+/// simple enough that a plain search works for any feed seen in practice.
+fn tag_contents<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut result = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        // Make sure it's not a longer tag sharing the prefix, like `<hourly>` for `<hour`.
+        if !rest.starts_with('>') && !rest.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let body = match rest.find('>') {
+            Some(i) => &rest[i + 1..],
+            None => break,
+        };
+        match body.find(&close) {
+            Some(end) => {
+                result.push(body[..end].trim());
+                rest = &body[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    result
+}
+
+impl Hints {
+    pub fn parse(xml: &[u8], feed: &feed_rs::model::Feed) -> Self {
+        let xml = String::from_utf8_lossy(xml);
+
+        let update_period = tag_contents(&xml, "sy:updatePeriod")
+            .first()
+            .and_then(|period| match period.to_lowercase().as_str() {
+                "hourly" => Some(60 * 60),
+                "daily" => Some(24 * 60 * 60),
+                "weekly" => Some(7 * 24 * 60 * 60),
+                "monthly" => Some(30 * 24 * 60 * 60),
+                "yearly" => Some(365 * 24 * 60 * 60),
+                _ => None,
+            })
+            .map(|period: i64| {
+                let frequency = tag_contents(&xml, "sy:updateFrequency")
+                    .first()
+                    .and_then(|f| f.parse::<i64>().ok())
+                    .filter(|&f| f > 0)
+                    .unwrap_or(1);
+                period / frequency
+            });
+
+        let mut skip_hours = 0;
+        for skip in tag_contents(&xml, "skipHours") {
+            for hour in tag_contents(skip, "hour") {
+                // Some feeds use 24 for midnight.
+                if let Ok(hour) = hour.parse::<u32>() {
+                    skip_hours |= 1 << (hour % 24);
+                }
+            }
+        }
+
+        let mut skip_days = 0;
+        for skip in tag_contents(&xml, "skipDays") {
+            for day in tag_contents(skip, "day") {
+                if let Some(i) = DAYS.iter().position(|d| d.eq_ignore_ascii_case(day)) {
+                    skip_days |= 1 << i;
+                }
+            }
+        }
+
+        Self {
+            ttl: feed.ttl.map(|ttl| ttl as i64 * 60),
+            update_period,
+            skip_hours,
+            skip_days,
+        }
+    }
+
+    /// Whether the feed asked not to be fetched at the given time.
+    pub fn skips(&self, when: DateTime<Utc>) -> bool {
+        self.skip_hours & (1 << when.hour()) != 0
+            || self.skip_days & (1 << when.weekday().num_days_from_monday()) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    static RSS: &str = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:sy="http://purl.org/rss/1.0/modules/syndication/">
+  <channel>
+    <title>Hints</title>
+    <ttl>60</ttl>
+    <sy:updatePeriod>daily</sy:updatePeriod>
+    <sy:updateFrequency>2</sy:updateFrequency>
+    <skipHours><hour>0</hour><hour>23</hour></skipHours>
+    <skipDays><day>Sunday</day></skipDays>
+  </channel>
+</rss>"#;
+
+    #[test]
+    fn check_feed_hints_parse() {
+        let feed = feed_rs::parser::parse(RSS.as_bytes()).unwrap();
+        let hints = Hints::parse(RSS.as_bytes(), &feed);
+        assert_eq!(hints.ttl, Some(60 * 60));
+        assert_eq!(hints.update_period, Some(12 * 60 * 60));
+        assert_eq!(hints.skip_hours, 1 | 1 << 23);
+        assert_eq!(hints.skip_days, 1 << 6);
+
+        // 2021-05-03 is a Monday.
+        assert!(!hints.skips(Utc.ymd(2021, 5, 3).and_hms(12, 0, 0)));
+        assert!(hints.skips(Utc.ymd(2021, 5, 3).and_hms(23, 0, 0)));
+        assert!(hints.skips(Utc.ymd(2021, 5, 9).and_hms(12, 0, 0)));
+    }
+}
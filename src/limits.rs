@@ -0,0 +1,198 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long the command rate is measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How many users to keep track of before forgetting those who are no longer limited.
+const MAX_TRACKED_USERS: usize = 1024;
+
+/// Limits for what users (other than the admins) can do, to keep anyone from abusing the bot.
+///
+/// `None` means unlimited.
+#[derive(Default)]
+pub struct Limits {
+    pub max_subscriptions: Option<usize>,
+    pub commands_per_minute: Option<usize>,
+    /// Failed `/add` in a row before having to wait `add_cooldown` to try again.
+    pub max_failed_adds: Option<u32>,
+    pub add_cooldown: Duration,
+    users: Mutex<HashMap<i64, Usage>>,
+}
+
+/// What a user did lately.
+#[derive(Default)]
+struct Usage {
+    /// When each of the commands within the window was sent.
+    commands: VecDeque<Instant>,
+    /// Whether they were already told to slow down, so they're only told once.
+    warned: bool,
+    failed_adds: u32,
+    cooldown_until: Option<Instant>,
+}
+
+impl Usage {
+    fn is_idle(&self, now: Instant) -> bool {
+        self.commands
+            .back()
+            .map_or(true, |&last| now.duration_since(last) >= RATE_WINDOW)
+            && self.failed_adds == 0
+            && self.cooldown_until.map_or(true, |until| until <= now)
+    }
+}
+
+/// The user's usage, forgetting the idle users first if there are too many, whichever limits are
+/// enabled.
+fn usage(users: &mut HashMap<i64, Usage>, user: i64, now: Instant) -> &mut Usage {
+    if users.len() >= MAX_TRACKED_USERS {
+        users.retain(|_, usage| !usage.is_idle(now));
+    }
+    users.entry(user).or_default()
+}
+
+/// Whether a command may go through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rate {
+    Allowed,
+    /// Too many, and the user should be told about it.
+    Exceeded,
+    /// Too many, and the user was already told.
+    Ignored,
+}
+
+impl Limits {
+    pub fn new(
+        max_subscriptions: Option<usize>,
+        commands_per_minute: Option<usize>,
+        max_failed_adds: Option<u32>,
+        add_cooldown: Duration,
+    ) -> Self {
+        Self {
+            max_subscriptions,
+            commands_per_minute,
+            max_failed_adds,
+            add_cooldown,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a command from the user, unless they already sent too many within the last minute.
+    pub fn command(&self, user: i64) -> Rate {
+        self.command_at(user, Instant::now())
+    }
+
+    fn command_at(&self, user: i64, now: Instant) -> Rate {
+        let limit = match self.commands_per_minute {
+            Some(limit) => limit,
+            None => return Rate::Allowed,
+        };
+        let mut users = self.users.lock().unwrap();
+        let usage = usage(&mut users, user, now);
+        while let Some(&first) = usage.commands.front() {
+            if now.duration_since(first) < RATE_WINDOW {
+                break;
+            }
+            usage.commands.pop_front();
+        }
+        if usage.commands.len() < limit {
+            usage.commands.push_back(now);
+            usage.warned = false;
+            Rate::Allowed
+        } else if usage.warned {
+            Rate::Ignored
+        } else {
+            usage.warned = true;
+            Rate::Exceeded
+        }
+    }
+
+    /// How much longer the user has to wait before trying to add feeds again, if at all.
+    pub fn add_cooldown(&self, user: i64) -> Option<Duration> {
+        self.add_cooldown_at(user, Instant::now())
+    }
+
+    fn add_cooldown_at(&self, user: i64, now: Instant) -> Option<Duration> {
+        let users = self.users.lock().unwrap();
+        let until = users.get(&user)?.cooldown_until?;
+        Some(until.saturating_duration_since(now)).filter(|&left| left > Duration::from_secs(0))
+    }
+
+    /// Record how trying to add a feed went. Too many failures in a row start the cooldown.
+    pub fn add_finished(&self, user: i64, ok: bool) {
+        self.add_finished_at(user, ok, Instant::now())
+    }
+
+    fn add_finished_at(&self, user: i64, ok: bool, now: Instant) {
+        let max_failed_adds = match self.max_failed_adds {
+            Some(max_failed_adds) => max_failed_adds,
+            None => return,
+        };
+        let mut users = self.users.lock().unwrap();
+        let usage = usage(&mut users, user, now);
+        if ok {
+            usage.failed_adds = 0;
+        } else {
+            usage.failed_adds += 1;
+            if usage.failed_adds >= max_failed_adds {
+                usage.failed_adds = 0;
+                usage.cooldown_until = Some(now + self.add_cooldown);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_command_rate() {
+        let limits = Limits::new(None, Some(2), None, Duration::from_secs(0));
+        let now = Instant::now();
+        assert_eq!(limits.command_at(1, now), Rate::Allowed);
+        assert_eq!(limits.command_at(1, now), Rate::Allowed);
+        assert_eq!(limits.command_at(1, now), Rate::Exceeded);
+        assert_eq!(limits.command_at(1, now), Rate::Ignored);
+        // Everyone has their own limit.
+        assert_eq!(limits.command_at(2, now), Rate::Allowed);
+
+        let later = now + RATE_WINDOW;
+        assert_eq!(limits.command_at(1, later), Rate::Allowed);
+        assert_eq!(limits.command_at(1, later), Rate::Allowed);
+        assert_eq!(limits.command_at(1, later), Rate::Exceeded);
+
+        assert_eq!(Limits::default().command_at(1, now), Rate::Allowed);
+    }
+
+    #[test]
+    fn check_add_cooldown() {
+        let cooldown = Duration::from_secs(600);
+        let limits = Limits::new(None, None, Some(2), cooldown);
+        let now = Instant::now();
+        limits.add_finished_at(1, false, now);
+        limits.add_finished_at(1, true, now);
+        limits.add_finished_at(1, false, now);
+        assert_eq!(limits.add_cooldown_at(1, now), None);
+
+        limits.add_finished_at(1, false, now);
+        assert_eq!(limits.add_cooldown_at(1, now), Some(cooldown));
+        assert_eq!(limits.add_cooldown_at(2, now), None);
+        assert_eq!(limits.add_cooldown_at(1, now + cooldown), None);
+    }
+
+    #[test]
+    fn check_idle_users_forgotten() {
+        let limits = Limits::new(None, None, Some(2), Duration::from_secs(600));
+        let now = Instant::now();
+        limits.add_finished_at(0, false, now);
+        for user in 1..=MAX_TRACKED_USERS as i64 * 2 {
+            limits.add_finished_at(user, true, now);
+        }
+        let users = limits.users.lock().unwrap();
+        assert!(users.len() <= MAX_TRACKED_USERS);
+        // Only those who are still limited are kept.
+        assert_eq!(users[&0].failed_adds, 1);
+    }
+}
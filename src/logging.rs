@@ -0,0 +1,42 @@
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+static FILTER: OnceCell<reload::Handle<EnvFilter, Registry>> = OnceCell::new();
+
+static NEXT_CORRELATION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Install the global subscriber, printing JSON lines if `json` is set.
+///
+/// `directives` use the `RUST_LOG` syntax, such as `info,srsrssrsbot::feed=debug`. Anything that
+/// fails to parse turns logging off, like an unknown level used to.
+pub fn init(directives: &str, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = EnvFilter::try_new(directives).unwrap_or_else(|_| EnvFilter::new("off"));
+    let (filter, handle) = reload::Layer::new(filter);
+    let registry = tracing_subscriber::registry().with(filter);
+    if json {
+        registry
+            .with(fmt::layer().json().with_current_span(true))
+            .try_init()?;
+    } else {
+        registry.with(fmt::layer()).try_init()?;
+    }
+    let _ = FILTER.set(handle);
+    Ok(())
+}
+
+/// Replace the filter at runtime, to change the level of some modules without restarting.
+pub fn set_filter(directives: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+    FILTER
+        .get()
+        .ok_or_else(|| "logging is not initialized".to_string())?
+        .reload(filter)
+        .map_err(|e| e.to_string())
+}
+
+/// A new ID to tell the logs of one fetch or delivery apart from the rest.
+pub fn correlation_id() -> u64 {
+    NEXT_CORRELATION_ID.fetch_add(1, Ordering::Relaxed)
+}
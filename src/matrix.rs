@@ -0,0 +1,193 @@
+use crate::feed::Change;
+use crate::string;
+use crate::transport::Error;
+use chrono::Utc;
+use reqwest::StatusCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Account on a Matrix homeserver which posts entries into rooms through the client-server API.
+pub struct Matrix {
+    homeserver: String,
+    token: String,
+    /// Transaction IDs must be unique per access token, even across restarts.
+    txn_prefix: i64,
+    txn_count: AtomicU64,
+}
+
+pub fn valid_room(room: &str) -> bool {
+    room.starts_with('!') && room.contains(':')
+}
+
+fn encode(segment: &str) -> String {
+    form_urlencoded::byte_serialize(segment.as_bytes()).collect()
+}
+
+impl Matrix {
+    pub fn new(homeserver: &str, token: &str) -> Self {
+        Self {
+            homeserver: homeserver.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            txn_prefix: Utc::now().timestamp_millis(),
+            txn_count: AtomicU64::new(0),
+        }
+    }
+
+    /// Post the change as a text message into the room, which the account must have joined.
+    pub async fn send(
+        &self,
+        http: &reqwest::Client,
+        room: &str,
+        change: &Change,
+    ) -> Result<(), Error> {
+        let entry = &change.entry;
+        let (body, formatted_body) = if change.updated {
+            (
+                string::updated_entry(entry),
+                string::updated_entry_html(entry),
+            )
+        } else {
+            (string::new_entry(entry), string::new_entry_html(entry))
+        };
+        let txn = format!(
+            "{}-{}",
+            self.txn_prefix,
+            self.txn_count.fetch_add(1, Ordering::Relaxed)
+        );
+        let url = format!(
+            "{}/_matrix/client/r0/rooms/{}/send/m.room.message/{}",
+            self.homeserver,
+            encode(room),
+            txn
+        );
+
+        let resp = http
+            .put(&url)
+            .bearer_auth(&self.token)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(
+                serde_json::json!({
+                    "msgtype": "m.text",
+                    "body": body,
+                    "format": "org.matrix.custom.html",
+                    "formatted_body": formatted_body,
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .map_err(|e| Error::Other(e.to_string()))?;
+
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let error = match resp.bytes().await {
+            Ok(body) => serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+            Err(_) => serde_json::Value::Null,
+        };
+        let errcode = error["errcode"].as_str().unwrap_or("").to_string();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after_ms = error["retry_after_ms"].as_u64().unwrap_or(0);
+            Err(Error::FloodWait(((retry_after_ms + 999) / 1000) as u32))
+        } else if status.is_client_error() {
+            Err(Error::Rejected(format!("{} {}", status, errcode)))
+        } else {
+            Err(Error::Other(format!("{} {}", status, errcode)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    static ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Test feed</title>
+  <id>urn:test</id>
+  <updated>2021-05-01T00:00:00Z</updated>
+  <entry>
+    <title>Entry &amp; more</title>
+    <id>urn:test:1</id>
+    <link href="https://example.com/1"/>
+    <updated>2021-05-01T00:00:00Z</updated>
+  </entry>
+</feed>"#;
+
+    /// Homeserver which accepts the first message and rate-limits the rest.
+    fn homeserver(received: Arc<Mutex<Vec<(String, String, serde_json::Value)>>>) -> String {
+        let make_svc = make_service_fn(move |_| {
+            let received = received.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let received = received.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let auth = req.headers()["authorization"].to_str().unwrap().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let body = serde_json::from_slice(&body).unwrap();
+
+                        let mut received = received.lock().unwrap();
+                        received.push((path, auth, body));
+                        Ok::<_, Infallible>(if received.len() == 1 {
+                            Response::new(Body::from(r#"{"event_id":"$1:example.com"}"#))
+                        } else {
+                            let mut resp = Response::new(Body::from(
+                                r#"{"errcode":"M_LIMIT_EXCEEDED","retry_after_ms":1500}"#,
+                            ));
+                            *resp.status_mut() = hyper::StatusCode::TOO_MANY_REQUESTS;
+                            resp
+                        })
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[tokio::test]
+    async fn check_matrix_message_sent() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let matrix = Matrix::new(&homeserver(received.clone()), "token");
+        let http = reqwest::Client::new();
+        let change = Change {
+            entry: feed_rs::parser::parse(ATOM.as_bytes())
+                .unwrap()
+                .entries
+                .remove(0),
+            updated: false,
+            previous: None,
+        };
+
+        matrix
+            .send(&http, "!room:example.com", &change)
+            .await
+            .unwrap();
+        assert!(matches!(
+            matrix.send(&http, "!room:example.com", &change).await,
+            Err(Error::FloodWait(2))
+        ));
+
+        let received = received.lock().unwrap();
+        let (path, auth, body) = &received[0];
+        assert!(
+            path.starts_with("/_matrix/client/r0/rooms/%21room%3Aexample.com/send/m.room.message/")
+        );
+        assert_ne!(path, &received[1].0);
+        assert_eq!(auth, "Bearer token");
+        assert_eq!(body["msgtype"], "m.text");
+        assert_eq!(body["body"], "Entry & more\nhttps://example.com/1");
+        assert_eq!(
+            body["formatted_body"],
+            r#"<a href="https://example.com/1">Entry &amp; more</a>"#
+        );
+    }
+}
@@ -0,0 +1,772 @@
+use crate::credentials::Credentials;
+use crate::email::{Digest, Item};
+use crate::feed::{self, Feed, Fingerprint, ScheduleSource, Stats, Subscriber};
+use crate::hints::Hints;
+use crate::request::RequestOptions;
+use crate::storage::{Error, FeedInfo, Result, Storage, Totals, PRUNED_ID_RETENTION};
+use crate::target::Target;
+use crate::websub::{self, Subscription};
+use async_trait::async_trait;
+use chrono::{TimeZone, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Storage that keeps everything in memory, for tests.
+///
+/// It behaves like the SQLite storage, except that credentials are kept as they are.
+#[derive(Default)]
+pub struct MemoryStorage(Mutex<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    last_feed_id: i64,
+    feeds: BTreeMap<i64, StoredFeed>,
+    /// Deliveries as `(feed_id, entry_id, hash, kind, user)`, like the `delivery` table.
+    deliveries: HashSet<(i64, String, String, i64, Vec<u8>)>,
+    daily_deliveries: HashMap<i64, i64>,
+    last_digest_id: i64,
+    digests: Vec<(i64, String, Item)>,
+    /// Reason and when, by user.
+    bans: BTreeMap<i64, (Option<String>, i64)>,
+    /// Whether loading feeds fails, as if the database had gone away.
+    unavailable: bool,
+}
+
+struct StoredFeed {
+    url: String,
+    credentials: Option<Credentials>,
+    last_check: i64,
+    next_check: i64,
+    scheduled_by: ScheduleSource,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    pruned_until: i64,
+    stats: Stats,
+    hints: Hints,
+    interval: Option<i64>,
+    ignore_cache: bool,
+    options: RequestOptions,
+    failures: i64,
+    last_error: Option<String>,
+    websub: Option<StoredWebsub>,
+    subscribers: Vec<Subscriber>,
+    entries: HashMap<String, StoredEntry>,
+    /// When each pruned entry was pruned.
+    pruned: HashMap<String, i64>,
+}
+
+struct StoredWebsub {
+    hub: String,
+    topic: String,
+    secret: String,
+    lease_until: i64,
+    requested: i64,
+}
+
+struct StoredEntry {
+    fingerprint: Fingerprint,
+    first_seen: i64,
+    last_seen: i64,
+}
+
+fn same_target(a: &Target, b: &Target) -> bool {
+    a.kind() == b.kind() && a.data() == b.data() && a.owner() == b.owner()
+}
+
+fn today() -> i64 {
+    Utc::now().timestamp() / (24 * 60 * 60)
+}
+
+impl StoredFeed {
+    fn subscriber_mut(&mut self, target: &Target) -> Option<&mut Subscriber> {
+        self.subscribers
+            .iter_mut()
+            .find(|sub| same_target(&sub.target, target))
+    }
+
+    fn is_subscribed(&self, target: &Target) -> bool {
+        self.subscribers
+            .iter()
+            .any(|sub| same_target(&sub.target, target))
+    }
+}
+
+impl Inner {
+    /// Delete the feeds along with their deliveries, returning how many there were.
+    fn remove_feeds(&mut self, remove: impl Fn(&StoredFeed) -> bool) -> usize {
+        let ids = self
+            .feeds
+            .iter()
+            .filter(|(_, feed)| remove(feed))
+            .map(|(&id, _)| id)
+            .collect::<HashSet<_>>();
+        self.feeds.retain(|id, _| !ids.contains(id));
+        self.deliveries
+            .retain(|delivery| !ids.contains(&delivery.0));
+        ids.len()
+    }
+
+    /// Save what changes with every check, like `db::save_feed`.
+    fn save_feed(&mut self, feed_id: i64, feed: &Feed) {
+        let stored = match self.feeds.get_mut(&feed_id) {
+            Some(stored) => stored,
+            None => return,
+        };
+        stored.last_check = feed.last_fetch.timestamp();
+        stored.next_check = feed.next_fetch_timestamp();
+        stored.scheduled_by = feed.scheduled_by;
+        stored.etag = feed.etag.clone();
+        stored.last_modified = feed.last_modified.clone();
+        stored.stats = feed.stats.clone();
+        stored.hints = feed.hints.clone();
+
+        if let Some(hub) = feed.hub.as_ref() {
+            match stored.websub.as_mut() {
+                Some(websub) if websub.hub == hub.hub && websub.topic == hub.topic => {}
+                Some(websub) => {
+                    websub.hub = hub.hub.clone();
+                    websub.topic = hub.topic.clone();
+                    websub.lease_until = 0;
+                    websub.requested = 0;
+                }
+                None => {
+                    stored.websub = Some(StoredWebsub {
+                        hub: hub.hub.clone(),
+                        topic: hub.topic.clone(),
+                        secret: websub::new_secret(),
+                        lease_until: 0,
+                        requested: 0,
+                    })
+                }
+            }
+        }
+
+        for (old_id, new_id) in feed.renamed_entries.iter() {
+            if let Some(entry) = stored.entries.remove(old_id) {
+                stored.entries.insert(new_id.clone(), entry);
+            }
+            self.deliveries
+                .retain(|delivery| delivery.0 != feed_id || &delivery.1 != old_id);
+        }
+
+        let now = Utc::now().timestamp();
+        for entry_id in feed.present_entries.iter() {
+            let fingerprint = match feed.seen_entries.get(entry_id) {
+                Some(fingerprint) => fingerprint,
+                None => continue,
+            };
+            let entry = stored
+                .entries
+                .entry(entry_id.clone())
+                .or_insert_with(|| StoredEntry {
+                    fingerprint: fingerprint.clone(),
+                    first_seen: now,
+                    last_seen: now,
+                });
+            entry.fingerprint = fingerprint.clone();
+            entry.last_seen = now;
+            stored.pruned.remove(entry_id);
+            self.deliveries
+                .retain(|delivery| delivery.0 != feed_id || &delivery.1 != entry_id);
+        }
+    }
+}
+
+impl MemoryStorage {
+    /// Make loading the state of feeds fail until it's made available again.
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.0.lock().unwrap().unavailable = unavailable;
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn can_store_credentials(&self) -> bool {
+        true
+    }
+
+    async fn add_feed(&self, feed: &Feed) -> Result<i64> {
+        let mut inner = self.0.lock().unwrap();
+        inner.remove_feeds(|stored| {
+            stored.url == feed.url && stored.credentials == feed.credentials
+        });
+        inner.last_feed_id += 1;
+        let feed_id = inner.last_feed_id;
+
+        let mut subscribers = Vec::<Subscriber>::new();
+        for sub in feed.users.iter() {
+            if !subscribers
+                .iter()
+                .any(|s| same_target(&s.target, &sub.target))
+            {
+                subscribers.push(sub.clone());
+            }
+        }
+        inner.feeds.insert(
+            feed_id,
+            StoredFeed {
+                url: feed.url.clone(),
+                credentials: feed.credentials.clone(),
+                last_check: 0,
+                next_check: 0,
+                scheduled_by: feed.scheduled_by,
+                etag: None,
+                last_modified: None,
+                pruned_until: 0,
+                stats: Stats::default(),
+                hints: Hints::default(),
+                interval: None,
+                ignore_cache: false,
+                options: RequestOptions::default(),
+                failures: 0,
+                last_error: None,
+                websub: None,
+                subscribers,
+                entries: HashMap::new(),
+                pruned: HashMap::new(),
+            },
+        );
+        inner.save_feed(feed_id, feed);
+        Ok(feed_id)
+    }
+
+    async fn update_feed(&self, feed: &Feed) -> Result<()> {
+        self.0.lock().unwrap().save_feed(feed.id, feed);
+        Ok(())
+    }
+
+    async fn remove_feed(&self, url: &str) -> Result<bool> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .remove_feeds(|stored| stored.url == url)
+            != 0)
+    }
+
+    async fn cleanup_feeds(&self) -> Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .remove_feeds(|stored| stored.subscribers.is_empty());
+        Ok(())
+    }
+
+    async fn load_feeds(&self) -> Result<Vec<Feed>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner
+            .feeds
+            .iter()
+            .map(|(&id, stored)| Feed {
+                id,
+                credentials: stored.credentials.clone(),
+                pruned_until: stored.pruned_until,
+                last_fetch: Utc.timestamp_opt(stored.last_check, 0).unwrap(),
+                next_fetch: feed::instant_at(stored.next_check),
+                scheduled_by: stored.scheduled_by,
+                interval: stored.interval,
+                ignore_cache: stored.ignore_cache,
+                etag: stored.etag.clone(),
+                last_modified: stored.last_modified.clone(),
+                stats: stored.stats.clone(),
+                hints: stored.hints.clone(),
+                ..Feed::for_test(&stored.url)
+            })
+            .collect())
+    }
+
+    async fn load_feed_state(&self, feed: &mut Feed) -> Result<bool> {
+        let inner = self.0.lock().unwrap();
+        if inner.unavailable {
+            return Err(Error::UnavailableError(
+                "the storage was made unavailable".to_string(),
+            ));
+        }
+        let stored = match inner.feeds.get(&feed.id) {
+            Some(stored) if !stored.subscribers.is_empty() => stored,
+            _ => return Ok(false),
+        };
+        feed.pruned_until = stored.pruned_until;
+        feed.interval = stored.interval;
+        feed.ignore_cache = stored.ignore_cache;
+        feed.options = stored.options.clone();
+        feed.push_until = stored
+            .websub
+            .as_ref()
+            .map_or(0, |websub| websub.lease_until);
+        feed.users = stored.subscribers.clone();
+        feed.seen_entries = stored
+            .entries
+            .iter()
+            .map(|(id, entry)| (id.clone(), entry.fingerprint.clone()))
+            .collect();
+        let latest = stored.entries.values().map(|entry| entry.last_seen).max();
+        feed.present_entries = stored
+            .entries
+            .iter()
+            .filter(|(_, entry)| Some(entry.last_seen) == latest)
+            .map(|(id, _)| id.clone())
+            .collect();
+        Ok(true)
+    }
+
+    async fn feed_ids(&self, url: &str, target: Option<&Target>) -> Result<Vec<i64>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner
+            .feeds
+            .iter()
+            .filter(|(_, stored)| {
+                stored.url == url && target.map_or(true, |target| stored.is_subscribed(target))
+            })
+            .map(|(&id, _)| id)
+            .collect())
+    }
+
+    async fn feed_summaries(&self) -> Result<Vec<(String, i64, i64, String, i64)>> {
+        let inner = self.0.lock().unwrap();
+        let mut result = inner
+            .feeds
+            .values()
+            .map(|stored| {
+                (
+                    stored.url.clone(),
+                    stored.last_check,
+                    stored.next_check,
+                    stored.scheduled_by.as_str().to_string(),
+                    stored.subscribers.len() as i64,
+                )
+            })
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(result)
+    }
+
+    async fn feed_info(&self, url: &str) -> Result<Vec<FeedInfo>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner
+            .feeds
+            .iter()
+            .filter(|(_, stored)| stored.url == url)
+            .map(|(&id, stored)| FeedInfo {
+                id,
+                has_credentials: stored.credentials.is_some(),
+                subscribers: stored.subscribers.len() as i64,
+                last_check: stored.last_check,
+                next_check: stored.next_check,
+                scheduled_by: stored.scheduled_by.as_str().to_string(),
+                etag: stored.etag.clone(),
+                last_modified: stored.last_modified.clone(),
+                failures: stored.failures,
+                last_error: stored.last_error.clone(),
+            })
+            .collect())
+    }
+
+    async fn record_check(&self, feed_id: i64, error: Option<&str>) -> Result<()> {
+        if let Some(stored) = self.0.lock().unwrap().feeds.get_mut(&feed_id) {
+            match error {
+                Some(error) => {
+                    stored.failures += 1;
+                    stored.last_error = Some(error.to_string());
+                }
+                None => {
+                    stored.failures = 0;
+                    stored.last_error = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn prune_entries(&self, until: i64) -> Result<usize> {
+        let mut inner = self.0.lock().unwrap();
+        let now = Utc::now().timestamp();
+        let mut count = 0;
+        for stored in inner.feeds.values_mut() {
+            stored
+                .pruned
+                .retain(|_, &mut pruned_at| pruned_at >= now - PRUNED_ID_RETENTION);
+            let latest = match stored.entries.values().map(|entry| entry.last_seen).max() {
+                Some(latest) => latest,
+                None => continue,
+            };
+            let gone = |entry: &StoredEntry| entry.last_seen < until && entry.last_seen < latest;
+            if let Some(first_seen) = stored
+                .entries
+                .values()
+                .filter(|entry| gone(entry))
+                .map(|entry| entry.first_seen)
+                .max()
+            {
+                stored.pruned_until = stored.pruned_until.max(first_seen);
+            }
+            let before = stored.entries.len();
+            let pruned = &mut stored.pruned;
+            stored.entries.retain(|entry_id, entry| {
+                if gone(entry) {
+                    pruned.insert(entry_id.clone(), now);
+                }
+                !gone(entry)
+            });
+            count += before - stored.entries.len();
+        }
+        Ok(count)
+    }
+
+    async fn pruned_entries(&self, feed_id: i64, entry_ids: &[String]) -> Result<HashSet<String>> {
+        let inner = self.0.lock().unwrap();
+        Ok(match inner.feeds.get(&feed_id) {
+            Some(stored) => entry_ids
+                .iter()
+                .filter(|entry_id| stored.pruned.contains_key(*entry_id))
+                .cloned()
+                .collect(),
+            None => HashSet::new(),
+        })
+    }
+
+    async fn was_delivered(
+        &self,
+        feed_id: i64,
+        entry_id: &str,
+        hash: &str,
+        target: &Target,
+    ) -> Result<bool> {
+        Ok(self.0.lock().unwrap().deliveries.contains(&(
+            feed_id,
+            entry_id.to_string(),
+            hash.to_string(),
+            target.kind(),
+            target.data(),
+        )))
+    }
+
+    async fn mark_delivered(
+        &self,
+        feed_id: i64,
+        entry_id: &str,
+        hash: &str,
+        target: &Target,
+    ) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        inner.deliveries.insert((
+            feed_id,
+            entry_id.to_string(),
+            hash.to_string(),
+            target.kind(),
+            target.data(),
+        ));
+        *inner.daily_deliveries.entry(today()).or_default() += 1;
+        Ok(())
+    }
+
+    async fn try_add_subscriber(
+        &self,
+        url: &str,
+        credentials: Option<&Credentials>,
+        target: &Target,
+    ) -> Result<bool> {
+        let mut inner = self.0.lock().unwrap();
+        match inner
+            .feeds
+            .values_mut()
+            .find(|stored| stored.url == url && stored.credentials.as_ref() == credentials)
+        {
+            Some(stored) => {
+                if !stored.is_subscribed(target) {
+                    stored.subscribers.push(Subscriber {
+                        target: target.clone(),
+                        notify_updates: false,
+                    });
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn try_del_subscriber(&self, url: &str, target: &Target) -> Result<bool> {
+        let mut inner = self.0.lock().unwrap();
+        let mut removed = false;
+        for stored in inner.feeds.values_mut().filter(|stored| stored.url == url) {
+            let before = stored.subscribers.len();
+            stored
+                .subscribers
+                .retain(|sub| !same_target(&sub.target, target));
+            removed |= stored.subscribers.len() != before;
+        }
+        Ok(removed)
+    }
+
+    async fn toggle_notify_updates(&self, url: &str, target: &Target) -> Result<Option<bool>> {
+        let mut inner = self.0.lock().unwrap();
+        let mut result = None;
+        for stored in inner.feeds.values_mut().filter(|stored| stored.url == url) {
+            if let Some(sub) = stored.subscriber_mut(target) {
+                sub.notify_updates = !sub.notify_updates;
+                result = result.or(Some(sub.notify_updates));
+            }
+        }
+        Ok(result)
+    }
+
+    async fn get_subscribers(&self, url: &str) -> Result<Vec<Subscriber>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner
+            .feeds
+            .values()
+            .filter(|stored| stored.url == url)
+            .flat_map(|stored| stored.subscribers.iter().cloned())
+            .collect())
+    }
+
+    async fn get_chat_subscribers(&self) -> Result<Vec<Target>> {
+        let inner = self.0.lock().unwrap();
+        let mut result = Vec::<Target>::new();
+        for sub in inner
+            .feeds
+            .values()
+            .flat_map(|stored| stored.subscribers.iter())
+        {
+            if let Target::Telegram(_) = sub.target {
+                if !result.iter().any(|target| same_target(target, &sub.target)) {
+                    result.push(sub.target.clone());
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    async fn count_subscriptions(&self, owner: i64) -> Result<usize> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner
+            .feeds
+            .values()
+            .flat_map(|stored| stored.subscribers.iter())
+            .filter(|sub| sub.target.owner() == owner)
+            .count())
+    }
+
+    async fn get_user_feeds(&self, target: &Target) -> Result<Vec<String>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner
+            .feeds
+            .values()
+            .filter(|stored| stored.is_subscribed(target))
+            .map(|stored| stored.url.clone())
+            .collect())
+    }
+
+    async fn get_user_hooks(&self, owner: i64) -> Result<Vec<(String, String)>> {
+        let inner = self.0.lock().unwrap();
+        let mut result = Vec::new();
+        for stored in inner.feeds.values() {
+            for sub in stored.subscribers.iter() {
+                if let Target::Webhook { url, owner: o, .. } = &sub.target {
+                    if *o == owner {
+                        result.push((stored.url.clone(), url.clone()));
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    async fn set_interval(&self, feed_id: i64, interval: Option<i64>) -> Result<bool> {
+        match self.0.lock().unwrap().feeds.get_mut(&feed_id) {
+            Some(stored) => {
+                stored.interval = interval;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn toggle_ignore_cache(&self, feed_id: i64) -> Result<Option<bool>> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .feeds
+            .get_mut(&feed_id)
+            .map(|stored| {
+                stored.ignore_cache = !stored.ignore_cache;
+                stored.ignore_cache
+            }))
+    }
+
+    async fn get_request_options(&self, feed_id: i64) -> Result<Option<RequestOptions>> {
+        Ok(self
+            .0
+            .lock()
+            .unwrap()
+            .feeds
+            .get(&feed_id)
+            .map(|stored| stored.options.clone()))
+    }
+
+    async fn set_request_options(&self, feed_id: i64, options: &RequestOptions) -> Result<()> {
+        if let Some(stored) = self.0.lock().unwrap().feeds.get_mut(&feed_id) {
+            stored.options = options.clone();
+        }
+        Ok(())
+    }
+
+    async fn get_websub(&self, feed_id: i64) -> Result<Option<Subscription>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner.feeds.get(&feed_id).and_then(|stored| {
+            stored.websub.as_ref().map(|websub| Subscription {
+                feed_id,
+                url: stored.url.clone(),
+                hub: websub.hub.clone(),
+                topic: websub.topic.clone(),
+                secret: websub.secret.clone(),
+                requested: websub.requested,
+            })
+        }))
+    }
+
+    async fn set_websub_lease(&self, feed_id: i64, lease_until: i64) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        if let Some(websub) = inner
+            .feeds
+            .get_mut(&feed_id)
+            .and_then(|stored| stored.websub.as_mut())
+        {
+            websub.lease_until = lease_until;
+        }
+        Ok(())
+    }
+
+    async fn mark_websub_requested(&self, feed_id: i64, requested: i64) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        if let Some(websub) = inner
+            .feeds
+            .get_mut(&feed_id)
+            .and_then(|stored| stored.websub.as_mut())
+        {
+            websub.requested = requested;
+        }
+        Ok(())
+    }
+
+    async fn websub_to_renew(&self, until: i64, retry_before: i64) -> Result<Vec<Subscription>> {
+        let inner = self.0.lock().unwrap();
+        Ok(inner
+            .feeds
+            .iter()
+            .filter_map(|(&feed_id, stored)| {
+                let websub = stored.websub.as_ref()?;
+                if websub.lease_until < until && websub.requested < retry_before {
+                    Some(Subscription {
+                        feed_id,
+                        url: stored.url.clone(),
+                        hub: websub.hub.clone(),
+                        topic: websub.topic.clone(),
+                        secret: websub.secret.clone(),
+                        requested: websub.requested,
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+
+    async fn add_to_digest(&self, address: &str, item: &Item) -> Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        inner.last_digest_id += 1;
+        let id = inner.last_digest_id;
+        inner.digests.push((id, address.to_string(), item.clone()));
+        Ok(())
+    }
+
+    async fn load_digests(&self) -> Result<Vec<Digest>> {
+        let inner = self.0.lock().unwrap();
+        let mut rows = inner.digests.iter().collect::<Vec<_>>();
+        rows.sort_by(|a, b| (&a.1, a.0).cmp(&(&b.1, b.0)));
+        let mut result = Vec::<Digest>::new();
+        for (id, address, item) in rows {
+            match result.last_mut() {
+                Some(digest) if &digest.address == address => {
+                    digest.items.push(item.clone());
+                    digest.last_id = *id;
+                }
+                _ => result.push(Digest {
+                    address: address.clone(),
+                    items: vec![item.clone()],
+                    last_id: *id,
+                }),
+            }
+        }
+        Ok(result)
+    }
+
+    async fn clear_digest(&self, digest: &Digest) -> Result<()> {
+        self.0
+            .lock()
+            .unwrap()
+            .digests
+            .retain(|(id, address, _)| *address != digest.address || *id > digest.last_id);
+        Ok(())
+    }
+
+    async fn ban(&self, user: i64, reason: Option<&str>) -> Result<bool> {
+        let mut inner = self.0.lock().unwrap();
+        if inner.bans.contains_key(&user) {
+            return Ok(false);
+        }
+        inner
+            .bans
+            .insert(user, (reason.map(String::from), Utc::now().timestamp()));
+        Ok(true)
+    }
+
+    async fn unban(&self, user: i64) -> Result<bool> {
+        Ok(self.0.lock().unwrap().bans.remove(&user).is_some())
+    }
+
+    async fn is_banned(&self, user: i64) -> Result<bool> {
+        Ok(self.0.lock().unwrap().bans.contains_key(&user))
+    }
+
+    async fn get_bans(&self) -> Result<Vec<(i64, Option<String>, i64)>> {
+        let inner = self.0.lock().unwrap();
+        let mut result = inner
+            .bans
+            .iter()
+            .map(|(&user, (reason, banned_at))| (user, reason.clone(), *banned_at))
+            .collect::<Vec<_>>();
+        result.sort_by_key(|&(_, _, banned_at)| banned_at);
+        Ok(result)
+    }
+
+    async fn totals(&self) -> Result<Totals> {
+        let inner = self.0.lock().unwrap();
+        let users = inner
+            .feeds
+            .values()
+            .flat_map(|stored| stored.subscribers.iter())
+            .map(|sub| (sub.target.kind(), sub.target.data()))
+            .collect::<HashSet<_>>();
+        Ok(Totals {
+            users: users.len() as i64,
+            feeds: inner.feeds.len() as i64,
+            entries: inner
+                .feeds
+                .values()
+                .map(|stored| stored.entries.len() as i64)
+                .sum(),
+            deliveries_today: inner.daily_deliveries.get(&today()).copied().unwrap_or(0),
+            failing_feeds: inner
+                .feeds
+                .values()
+                .filter(|stored| stored.failures > 0)
+                .count() as i64,
+        })
+    }
+
+    async fn check_writable(&self) -> Result<()> {
+        Ok(())
+    }
+}
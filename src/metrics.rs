@@ -0,0 +1,308 @@
+use crate::health;
+use crate::scheduler::Scheduler;
+use crate::storage::Storage;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds (in seconds) of the histogram buckets. The last bucket is implicitly `+Inf`.
+const BUCKETS: [f64; 10] = [0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// A feed whose fetches failed this many times in a row is considered dead.
+const DEAD_AFTER_FAILURES: u32 = 5;
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Clone, Default)]
+struct Inner {
+    /// Fetch durations by status code, with `0` for requests that got no response at all.
+    fetches: BTreeMap<u16, Histogram>,
+    /// Consecutive failed fetches per feed, only for feeds whose last fetch failed.
+    failing: HashMap<String, u32>,
+    delivered: u64,
+    /// Failed deliveries by error kind.
+    failed: BTreeMap<&'static str, u64>,
+    flood_wait_seconds: u64,
+    /// Transaction durations by operation.
+    transactions: BTreeMap<&'static str, Histogram>,
+    scheduler_lag: Histogram,
+}
+
+/// Counters for operations, exposed in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics(Mutex<Inner>);
+
+/// Records how long a database transaction took when dropped.
+pub struct TransactionTimer {
+    op: &'static str,
+    start: Instant,
+}
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, sep, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, sep, self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+impl Metrics {
+    /// Record a fetch that got a response with the given status, or `None` if it failed outright.
+    pub fn fetched(&self, status: Option<u16>, duration: Duration) {
+        let mut inner = self.0.lock().unwrap();
+        inner
+            .fetches
+            .entry(status.unwrap_or(0))
+            .or_default()
+            .observe(duration);
+    }
+
+    /// Record whether the feed could be fetched and parsed, to tell which feeds are dead.
+    pub fn feed_checked(&self, url: &str, ok: bool) {
+        let mut inner = self.0.lock().unwrap();
+        if ok {
+            inner.failing.remove(url);
+        } else {
+            *inner.failing.entry(url.to_string()).or_default() += 1;
+        }
+    }
+
+    /// Forget about a feed that is no longer checked.
+    pub fn feed_removed(&self, url: &str) {
+        self.0.lock().unwrap().failing.remove(url);
+    }
+
+    pub fn delivered(&self) {
+        self.0.lock().unwrap().delivered += 1;
+    }
+
+    pub fn delivery_failed(&self, err: &crate::transport::Error) {
+        let mut inner = self.0.lock().unwrap();
+        *inner.failed.entry(err.kind()).or_default() += 1;
+        if let crate::transport::Error::FloodWait(secs) = err {
+            inner.flood_wait_seconds += *secs as u64;
+        }
+    }
+
+    /// How long after it was due a feed was actually taken to be checked.
+    pub fn scheduler_lag(&self, lag: Duration) {
+        self.0.lock().unwrap().scheduler_lag.observe(lag);
+    }
+
+    pub fn transaction(&self, op: &'static str) -> TransactionTimer {
+        TransactionTimer {
+            op,
+            start: Instant::now(),
+        }
+    }
+
+    /// Render everything in the Prometheus text exposition format.
+    pub fn render(&self, scheduler: &Scheduler) -> String {
+        let (total, due) = scheduler.counts();
+        let inner = self.0.lock().unwrap().clone();
+        let mut out = String::new();
+
+        let dead = inner
+            .failing
+            .values()
+            .filter(|&&failures| failures >= DEAD_AFTER_FAILURES)
+            .count();
+        let _ = writeln!(out, "# HELP srsrssrs_feeds Feeds known to the scheduler.");
+        let _ = writeln!(out, "# TYPE srsrssrs_feeds gauge");
+        let _ = writeln!(out, "srsrssrs_feeds{{state=\"total\"}} {}", total);
+        let _ = writeln!(out, "srsrssrs_feeds{{state=\"due\"}} {}", due);
+        let _ = writeln!(out, "srsrssrs_feeds{{state=\"dead\"}} {}", dead);
+
+        let _ = writeln!(out, "# HELP srsrssrs_fetch_seconds Feed fetch latency by status code (0 if there was no response).");
+        let _ = writeln!(out, "# TYPE srsrssrs_fetch_seconds histogram");
+        for (status, histogram) in inner.fetches.iter() {
+            histogram.render(
+                &mut out,
+                "srsrssrs_fetch_seconds",
+                &format!("status=\"{}\"", status),
+            );
+        }
+
+        let responses = inner
+            .fetches
+            .iter()
+            .filter(|(&status, _)| status != 0)
+            .map(|(_, histogram)| histogram.count)
+            .sum::<u64>();
+        let not_modified = inner.fetches.get(&304).map_or(0, |h| h.count);
+        let _ = writeln!(
+            out,
+            "# HELP srsrssrs_not_modified_ratio Share of fetches answered with 304 Not Modified."
+        );
+        let _ = writeln!(out, "# TYPE srsrssrs_not_modified_ratio gauge");
+        let _ = writeln!(
+            out,
+            "srsrssrs_not_modified_ratio {}",
+            if responses == 0 {
+                0.0
+            } else {
+                not_modified as f64 / responses as f64
+            }
+        );
+
+        let _ = writeln!(out, "# HELP srsrssrs_deliveries_total Entries delivered to subscribers, and failures by error kind.");
+        let _ = writeln!(out, "# TYPE srsrssrs_deliveries_total counter");
+        let _ = writeln!(
+            out,
+            "srsrssrs_deliveries_total{{result=\"ok\"}} {}",
+            inner.delivered
+        );
+        for (kind, count) in inner.failed.iter() {
+            let _ = writeln!(
+                out,
+                "srsrssrs_deliveries_total{{result=\"{}\"}} {}",
+                kind, count
+            );
+        }
+
+        let _ = writeln!(out, "# HELP srsrssrs_flood_wait_seconds_total Seconds the messenger asked to wait before sending more.");
+        let _ = writeln!(out, "# TYPE srsrssrs_flood_wait_seconds_total counter");
+        let _ = writeln!(
+            out,
+            "srsrssrs_flood_wait_seconds_total {}",
+            inner.flood_wait_seconds
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP srsrssrs_db_transaction_seconds Database transaction duration by operation."
+        );
+        let _ = writeln!(out, "# TYPE srsrssrs_db_transaction_seconds histogram");
+        for (op, histogram) in inner.transactions.iter() {
+            histogram.render(
+                &mut out,
+                "srsrssrs_db_transaction_seconds",
+                &format!("op=\"{}\"", op),
+            );
+        }
+
+        let _ = writeln!(out, "# HELP srsrssrs_scheduler_lag_seconds Time between a feed being due and it being checked.");
+        let _ = writeln!(out, "# TYPE srsrssrs_scheduler_lag_seconds histogram");
+        inner
+            .scheduler_lag
+            .render(&mut out, "srsrssrs_scheduler_lag_seconds", "");
+
+        out
+    }
+}
+
+impl Drop for TransactionTimer {
+    fn drop(&mut self) {
+        let mut inner = METRICS.0.lock().unwrap();
+        inner
+            .transactions
+            .entry(self.op)
+            .or_default()
+            .observe(self.start.elapsed());
+    }
+}
+
+struct State {
+    db: Arc<dyn Storage>,
+    scheduler: Arc<Scheduler>,
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    Ok(match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let mut response = Response::new(Body::from(metrics().render(&state.scheduler)));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                "text/plain; version=0.0.4".parse().unwrap(),
+            );
+            response
+        }
+        (&Method::GET, "/healthz") => health::response(state.db.as_ref(), &state.scheduler).await,
+        _ => {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            response
+        }
+    })
+}
+
+/// Serve the metrics at `/metrics` and the health check at `/healthz` on `addr`.
+///
+/// The health check answers `503 Service Unavailable` if something is wrong.
+pub async fn run(
+    addr: SocketAddr,
+    db: Arc<dyn Storage>,
+    scheduler: Arc<Scheduler>,
+) -> Result<(), hyper::Error> {
+    let state = Arc::new(State { db, scheduler });
+    let make_svc = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+    });
+    Server::bind(&addr).serve(make_svc).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_histogram_render() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(30));
+        histogram.observe(Duration::from_secs(60));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test_seconds", "status=\"200\"");
+        assert!(out.contains("test_seconds_bucket{status=\"200\",le=\"0.01\"} 0\n"));
+        assert!(out.contains("test_seconds_bucket{status=\"200\",le=\"0.05\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{status=\"200\",le=\"30\"} 1\n"));
+        assert!(out.contains("test_seconds_bucket{status=\"200\",le=\"+Inf\"} 2\n"));
+        assert!(out.contains("test_seconds_count{status=\"200\"} 2\n"));
+    }
+}
@@ -0,0 +1,186 @@
+use reqwest::Url;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Whether the address is on the internet, rather than this machine or a private network.
+///
+/// `allow_loopback` lets this machine through anyway, which only the tests need to reach the
+/// servers they start.
+pub fn is_public(ip: IpAddr, allow_loopback: bool) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip, allow_loopback),
+        IpAddr::V6(ip) => {
+            if ip.is_loopback() {
+                return allow_loopback;
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local and link-local.
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80)
+                // IPv4 addresses written as IPv6 ones.
+                && ip
+                    .to_ipv4()
+                    .map_or(true, |ip| is_public_v4(ip, allow_loopback))
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr, allow_loopback: bool) -> bool {
+    if ip.is_loopback() {
+        return allow_loopback;
+    }
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "This network", which reaches this machine.
+        || a == 0
+        // Shared between the customers of a carrier-grade NAT.
+        || (a == 100 && b & 0xc0 == 64)
+        || a >= 240)
+}
+
+/// The URL's host, without the brackets around IPv6 addresses.
+fn host(url: &Url) -> Option<&str> {
+    url.host_str()
+        .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+}
+
+/// Whether the URL may be requested, as far as can be told without resolving it: addresses must be
+/// public, and `localhost` is refused. The parser turns numbers like `127.1` or `0x7f.1` into the
+/// address they stand for first.
+pub fn is_public_url(url: &str, allow_loopback: bool) -> bool {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    match host(&url) {
+        Some(host) => match host.parse::<IpAddr>() {
+            Ok(ip) => is_public(ip, allow_loopback),
+            Err(_) => {
+                let host = host.trim_end_matches('.');
+                allow_loopback || (host != "localhost" && !host.ends_with(".localhost"))
+            }
+        },
+        None => false,
+    }
+}
+
+/// Whether the URL is for a web page, which is the only kind of link worth making clickable.
+pub fn is_web_url(url: &str) -> bool {
+    Url::parse(url).map_or(false, |url| {
+        url.scheme() == "http" || url.scheme() == "https"
+    })
+}
+
+/// Whether every address the URL's host resolves to is public.
+///
+/// Anything anyone can point anywhere, like webhooks, should be checked with this right before
+/// each request, as the addresses may have changed since it was added.
+pub async fn resolves_publicly(url: &str, allow_loopback: bool) -> bool {
+    if !is_public_url(url, allow_loopback) {
+        return false;
+    }
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    let (host, port) = match (host(&url), url.port_or_known_default()) {
+        (Some(host), Some(port)) => (host, port),
+        _ => return false,
+    };
+    match tokio::net::lookup_host((host, port)).await {
+        Ok(addrs) => {
+            let addrs = addrs.collect::<Vec<_>>();
+            !addrs.is_empty()
+                && addrs
+                    .iter()
+                    .all(|addr| is_public(addr.ip(), allow_loopback))
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_private_addresses_refused() {
+        for ip in [
+            "10.0.0.1",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:10.0.0.1",
+            "127.0.0.1",
+            "::1",
+            "::ffff:127.0.0.1",
+        ]
+        .iter()
+        {
+            assert!(
+                !is_public(ip.parse().unwrap(), false),
+                "{} is not public",
+                ip
+            );
+        }
+        for ip in ["1.1.1.1", "2606:4700::1111"].iter() {
+            assert!(is_public(ip.parse().unwrap(), false), "{} is public", ip);
+        }
+        // Only this machine is let through for the tests, not the rest of its network.
+        assert!(is_public("127.0.0.1".parse().unwrap(), true));
+        assert!(is_public("::1".parse().unwrap(), true));
+        assert!(!is_public("10.0.0.1".parse().unwrap(), true));
+    }
+
+    #[test]
+    fn check_url_hosts() {
+        assert!(is_public_url("https://example.com/atom.xml", false));
+        assert!(!is_public_url("http://0x0a.1/atom.xml", false));
+        assert!(!is_public_url("http://[fd00::1]:8080/atom.xml", false));
+        assert!(!is_public_url(
+            "http://169.254.169.254/latest/meta-data",
+            false
+        ));
+        assert!(!is_public_url("not a url", false));
+        for url in [
+            "http://127.0.0.1/atom.xml",
+            "http://127.1/atom.xml",
+            "http://[::1]/atom.xml",
+            "http://0.0.0.0/atom.xml",
+            "http://localhost/atom.xml",
+            "http://LOCALHOST./atom.xml",
+            "http://feeds.localhost/atom.xml",
+        ]
+        .iter()
+        {
+            assert!(!is_public_url(url, false), "{} is this machine", url);
+            assert_eq!(
+                is_public_url(url, true),
+                !url.contains("0.0.0.0"),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn check_web_urls() {
+        assert!(is_web_url("https://example.com/1"));
+        assert!(is_web_url("HTTP://example.com/1"));
+        assert!(!is_web_url("javascript:alert(1)"));
+        assert!(!is_web_url("data:text/html,<script>alert(1)</script>"));
+        assert!(!is_web_url("(no online url)"));
+    }
+}
@@ -0,0 +1,183 @@
+use once_cell::sync::Lazy;
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::{Certificate, Client, Proxy, RequestBuilder};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Headers that are already sent by other means, and would conflict with them.
+const RESERVED_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "host",
+    "if-modified-since",
+    "if-none-match",
+];
+
+/// Clients for the feeds with their own proxy or CA bundle, shared by every feed with the same.
+///
+/// The CA bundle is only read when its client is built, so changes to the file need a restart.
+static CLIENTS: Lazy<Mutex<HashMap<(Option<String>, Option<String>), Client>>> =
+    Lazy::new(Default::default);
+
+/// How the requests for a feed are made, beyond its URL and credentials.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestOptions {
+    /// Proxy for every request, like `http://proxy:3128` or `socks5://proxy:1080`.
+    pub proxy: Option<String>,
+    /// Extra headers, which replace the defaults of the same name (like `User-Agent`).
+    pub headers: Vec<(String, String)>,
+    /// Path to a PEM file with the root certificates to trust besides the system ones.
+    pub ca_bundle: Option<String>,
+}
+
+/// Parse a header like `User-Agent: MyReader/1.0`, or just its name without the value.
+pub fn parse_header(text: &str) -> Option<(String, Option<String>)> {
+    let mut parts = text.splitn(2, ':');
+    let name = parts.next()?.trim();
+    let value = parts
+        .next()
+        .map(str::trim)
+        .filter(|value| !value.is_empty());
+    let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+    if RESERVED_HEADERS.contains(&name.as_str()) {
+        return None;
+    }
+    if let Some(value) = value {
+        HeaderValue::from_str(value).ok()?;
+    }
+    Some((name.as_str().to_string(), value.map(String::from)))
+}
+
+/// Split a PEM file into each of the certificates in it.
+fn parse_bundle(pem: &[u8]) -> Result<Vec<Certificate>, String> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let pem = std::str::from_utf8(pem).map_err(|_| "not a PEM file".to_string())?;
+    let certs = pem
+        .split_inclusive(END)
+        .filter(|cert| cert.contains(END))
+        .map(|cert| Certificate::from_pem(cert.as_bytes()).map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        Err("no certificates found".to_string())
+    } else {
+        Ok(certs)
+    }
+}
+
+impl RequestOptions {
+    /// The client to make the requests with, which is `default` unless a proxy or CA bundle is set.
+    pub fn client(&self, default: &Client) -> Result<Client, String> {
+        if self.proxy.is_none() && self.ca_bundle.is_none() {
+            return Ok(default.clone());
+        }
+
+        let key = (self.proxy.clone(), self.ca_bundle.clone());
+        if let Some(client) = CLIENTS.lock().unwrap().get(&key) {
+            return Ok(client.clone());
+        }
+
+        let mut builder = Client::builder();
+        if let Some(proxy) = self.proxy.as_ref() {
+            builder = builder.proxy(Proxy::all(proxy).map_err(|e| e.to_string())?);
+        }
+        if let Some(path) = self.ca_bundle.as_ref() {
+            let pem = std::fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+            for cert in parse_bundle(&pem)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        let client = builder.build().map_err(|e| e.to_string())?;
+        CLIENTS.lock().unwrap().insert(key, client.clone());
+        Ok(client)
+    }
+
+    /// Attach the extra headers to a request.
+    pub fn apply(&self, mut request: RequestBuilder) -> RequestBuilder {
+        for (name, value) in self.headers.iter() {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        request
+    }
+
+    /// Send the header with the value from now on, or stop sending it without one.
+    pub fn set_header(&mut self, name: &str, value: Option<String>) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        if let Some(value) = value {
+            self.headers.push((name.to_string(), value));
+        }
+    }
+
+    /// The headers as stored, one `Name: value` per line, or `None` without any.
+    pub fn headers_text(&self) -> Option<String> {
+        if self.headers.is_empty() {
+            return None;
+        }
+        Some(
+            self.headers
+                .iter()
+                .map(|(name, value)| format!("{}: {}", name, value))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
+    }
+
+    /// Parse what `headers_text` returned, skipping anything that's no longer valid.
+    pub fn parse_headers_text(text: &str) -> Vec<(String, String)> {
+        text.lines()
+            .filter_map(parse_header)
+            .filter_map(|(name, value)| Some((name, value?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_headers() {
+        assert_eq!(
+            parse_header("User-Agent: MyReader/1.0 (+https://example.com)"),
+            Some((
+                "user-agent".to_string(),
+                Some("MyReader/1.0 (+https://example.com)".to_string())
+            ))
+        );
+        assert_eq!(parse_header("Accept"), Some(("accept".to_string(), None)));
+        assert_eq!(parse_header("Bad Name: value"), None);
+        assert_eq!(parse_header("Authorization: Bearer secret"), None);
+
+        let mut options = RequestOptions::default();
+        options.set_header("accept", Some("application/atom+xml".to_string()));
+        options.set_header("user-agent", Some("MyReader/1.0".to_string()));
+        options.set_header("accept", Some("application/rss+xml".to_string()));
+        let text = options.headers_text().unwrap();
+        assert_eq!(
+            text,
+            "user-agent: MyReader/1.0\naccept: application/rss+xml"
+        );
+        assert_eq!(RequestOptions::parse_headers_text(&text), options.headers);
+
+        options.set_header("user-agent", None);
+        options.set_header("accept", None);
+        assert_eq!(options.headers_text(), None);
+    }
+
+    #[test]
+    fn check_clients_built() {
+        let default = Client::new();
+        let options = RequestOptions {
+            proxy: Some("socks5://127.0.0.1:1080".to_string()),
+            ..Default::default()
+        };
+        assert!(options.client(&default).is_ok());
+        assert!(options.client(&default).is_ok());
+
+        let missing = RequestOptions {
+            ca_bundle: Some("/nonexistent/ca.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(missing.client(&default).is_err());
+        assert!(parse_bundle(b"not a certificate").is_err());
+    }
+}
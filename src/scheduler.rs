@@ -0,0 +1,164 @@
+use crate::feed::Feed;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, Instant};
+
+/// In-memory queue of feeds ordered by when they're next due.
+///
+/// Only what's needed to schedule the feeds is kept. Subscribers and seen entries are loaded from
+/// the database when the feed is due, and dropped again once it's pushed back.
+pub struct Scheduler {
+    queue: Mutex<BinaryHeap<Reverse<Feed>>>,
+    wake: Notify,
+}
+
+impl Scheduler {
+    pub fn new(feeds: Vec<Feed>) -> Self {
+        Self {
+            queue: Mutex::new(
+                feeds
+                    .into_iter()
+                    .map(|mut feed| {
+                        feed.unload();
+                        Reverse(feed)
+                    })
+                    .collect(),
+            ),
+            wake: Notify::new(),
+        }
+    }
+
+    /// Queue the feed, waking up whoever is waiting in case it's due sooner.
+    pub fn push(&self, mut feed: Feed) {
+        feed.unload();
+        self.queue.lock().unwrap().push(Reverse(feed));
+        self.wake.notify_one();
+    }
+
+    /// Take the feed out of the queue regardless of when it's due.
+    pub fn take(&self, id: i64) -> Option<Feed> {
+        let mut queue = self.queue.lock().unwrap();
+        let mut feeds = std::mem::take(&mut *queue).into_vec();
+        let feed = feeds
+            .iter()
+            .position(|f| f.0.id == id)
+            .map(|i| feeds.swap_remove(i).0);
+        *queue = feeds.into();
+        feed
+    }
+
+    /// How many feeds are queued, and how many of those are already due.
+    pub fn counts(&self) -> (usize, usize) {
+        let queue = self.queue.lock().unwrap();
+        let now = Instant::now();
+        let due = queue.iter().filter(|f| f.0.next_fetch <= now).count();
+        (queue.len(), due)
+    }
+
+    /// How long the feed that was due first has been waiting, or zero if none is due yet.
+    pub fn overdue(&self) -> Duration {
+        let queue = self.queue.lock().unwrap();
+        queue.peek().map_or(Duration::from_secs(0), |f| {
+            Instant::now().saturating_duration_since(f.0.next_fetch)
+        })
+    }
+
+    /// Wait until at least one feed is due, and take all of those which are.
+    pub async fn wait_due(&self) -> Vec<Feed> {
+        loop {
+            let next_fetch = {
+                let mut queue = self.queue.lock().unwrap();
+                let now = Instant::now();
+                let mut due = Vec::new();
+                while queue.peek().filter(|f| f.0.next_fetch <= now).is_some() {
+                    due.push(queue.pop().unwrap().0);
+                }
+                if !due.is_empty() {
+                    return due;
+                }
+                queue.peek().map(|f| f.0.next_fetch)
+            };
+
+            // `Notify` keeps a permit if nobody was waiting, so a `push` that happens right after
+            // releasing the lock still wakes us up.
+            match next_fetch {
+                Some(when) => {
+                    tokio::select! {
+                        _ = sleep_until(when) => {}
+                        _ = self.wake.notified() => {}
+                    }
+                }
+                None => self.wake.notified().await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::time::timeout;
+
+    fn feed(id: i64, next_fetch: Instant) -> Feed {
+        Feed {
+            id,
+            next_fetch,
+            ..Feed::for_test(&format!("https://example.com/{}.xml", id))
+        }
+    }
+
+    fn ids(feeds: &[Feed]) -> Vec<i64> {
+        feeds.iter().map(|feed| feed.id).collect()
+    }
+
+    #[tokio::test]
+    async fn check_only_due_feeds_taken() {
+        let now = Instant::now();
+        let scheduler = Scheduler::new(vec![
+            feed(1, now + Duration::from_secs(60)),
+            feed(2, now - Duration::from_secs(1)),
+            feed(3, now - Duration::from_secs(2)),
+        ]);
+        assert_eq!(scheduler.counts(), (3, 2));
+        assert!(scheduler.overdue() >= Duration::from_secs(2));
+
+        // The one that was due first comes first.
+        assert_eq!(ids(&scheduler.wait_due().await), vec![3, 2]);
+        assert_eq!(scheduler.counts(), (1, 0));
+        assert_eq!(scheduler.overdue(), Duration::from_secs(0));
+
+        // Feeds taken out are gone until they're pushed back.
+        assert!(scheduler.take(2).is_none());
+        let mut taken = scheduler.take(1).unwrap();
+        assert_eq!(scheduler.counts(), (0, 0));
+        taken.next_fetch = now;
+        scheduler.push(taken);
+        assert_eq!(ids(&scheduler.wait_due().await), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn check_push_wakes_up_waiter() {
+        let scheduler = Arc::new(Scheduler::new(vec![feed(
+            1,
+            Instant::now() + Duration::from_secs(60 * 60),
+        )]));
+        let waiter = tokio::spawn({
+            let scheduler = Arc::clone(&scheduler);
+            async move { scheduler.wait_due().await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // The new feed is due long before the one that was being waited for.
+        scheduler.push(feed(2, Instant::now()));
+        let due = timeout(Duration::from_secs(5), waiter)
+            .await
+            .expect("pushing a due feed should wake the waiter up")
+            .unwrap();
+        assert_eq!(ids(&due), vec![2]);
+        assert_eq!(scheduler.counts(), (1, 0));
+    }
+}
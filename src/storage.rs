@@ -0,0 +1,385 @@
+use crate::credentials::Credentials;
+use crate::email::{Digest, Item};
+use crate::feed::{Feed, Subscriber};
+use crate::request::RequestOptions;
+use crate::target::Target;
+use crate::websub::Subscription;
+use async_trait::async_trait;
+use std::collections::HashSet;
+use std::fmt;
+
+/// How long the IDs of pruned entries are remembered, in seconds, to tell them apart from new ones
+/// if they reappear.
+pub const PRUNED_ID_RETENTION: i64 = 365 * 24 * 60 * 60;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The SQLite database couldn't run the query.
+    SqliteError(sqlite::Error),
+    /// A feed was given credentials, but there is no key to encrypt them with.
+    NoVaultError,
+    /// The database has no version, so there's no telling what its tables look like.
+    MissingVersionError,
+    /// The database was upgraded by a newer build, whose tables this one doesn't know.
+    TooNewError(i64),
+    /// The database has yet to be upgraded, which wasn't done as it was opened read-only.
+    TooOldError(i64),
+    /// A query panicked, and its connection can't be trusted to be in a usable state anymore.
+    UnavailableError(String),
+}
+
+/// Totals over the whole database, as shown by `/stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Totals {
+    pub users: i64,
+    pub feeds: i64,
+    pub entries: i64,
+    pub deliveries_today: i64,
+    pub failing_feeds: i64,
+}
+
+/// A feed as shown by `/feedinfo`. Times are Unix timestamps.
+#[derive(Debug, Clone)]
+pub struct FeedInfo {
+    pub id: i64,
+    pub has_credentials: bool,
+    pub subscribers: i64,
+    pub last_check: i64,
+    pub next_check: i64,
+    pub scheduled_by: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub failures: i64,
+    pub last_error: Option<String>,
+}
+
+/// Everything the bot needs to remember about feeds, their entries, subscribers and settings.
+///
+/// Feeds are identified by their ID. The same URL can be several feeds, one per set of credentials.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Whether feeds can be given credentials, which have to be stored safely.
+    fn can_store_credentials(&self) -> bool;
+
+    /// Store a new feed along with its subscribers, returning the ID it was given.
+    ///
+    /// It replaces any feed with the same URL and credentials.
+    async fn add_feed(&self, feed: &Feed) -> Result<i64>;
+
+    /// Save the state of a feed that was just checked, along with the entries it has seen.
+    ///
+    /// Everything is saved or nothing is, and a feed which no longer exists is ignored.
+    async fn update_feed(&self, feed: &Feed) -> Result<()>;
+
+    /// Delete the feeds with the URL along with their subscribers and entries, whichever
+    /// credentials they use. Returns `false` if there were none.
+    async fn remove_feed(&self, url: &str) -> Result<bool>;
+
+    /// Delete the feeds nobody is subscribed to anymore.
+    async fn cleanup_feeds(&self) -> Result<()>;
+
+    /// Load every feed without its subscribers or entries, to be used by the scheduler.
+    async fn load_feeds(&self) -> Result<Vec<Feed>>;
+
+    /// Load the subscribers and seen entries of a feed that's about to be checked.
+    ///
+    /// Returns `false` if the feed no longer exists or nobody is subscribed to it anymore.
+    async fn load_feed_state(&self, feed: &mut Feed) -> Result<bool>;
+
+    /// The feeds with the URL, or only those the target is subscribed to if there is one.
+    async fn feed_ids(&self, url: &str, target: Option<&Target>) -> Result<Vec<i64>>;
+
+    /// Every feed as `(url, last_check, next_check, scheduled_by, subscriber count)`.
+    async fn feed_summaries(&self) -> Result<Vec<(String, i64, i64, String, i64)>>;
+
+    /// What's known about each of the feeds with the URL, one per set of credentials.
+    async fn feed_info(&self, url: &str) -> Result<Vec<FeedInfo>>;
+
+    /// Record whether checking the feed worked, keeping count of how many times in a row it didn't.
+    async fn record_check(&self, feed_id: i64, error: Option<&str>) -> Result<()>;
+
+    /// Delete entries which are no longer in their feed and were last seen before `until`.
+    ///
+    /// An entry is considered to no longer be in the feed if it was not present in the most recent
+    /// fetch. Their IDs are kept for `PRUNED_ID_RETENTION`, so that they're not notified again if
+    /// they reappear.
+    async fn prune_entries(&self, until: i64) -> Result<usize>;
+
+    /// Which of the entries of the feed were pruned, as opposed to never seen.
+    async fn pruned_entries(&self, feed_id: i64, entry_ids: &[String]) -> Result<HashSet<String>>;
+
+    /// Whether this version of the entry was already delivered to the target.
+    async fn was_delivered(
+        &self,
+        feed_id: i64,
+        entry_id: &str,
+        hash: &str,
+        target: &Target,
+    ) -> Result<bool>;
+
+    /// Remember that this version of the entry was delivered to the target, until the entry is saved.
+    async fn mark_delivered(
+        &self,
+        feed_id: i64,
+        entry_id: &str,
+        hash: &str,
+        target: &Target,
+    ) -> Result<()>;
+
+    /// Subscribe the target to the feed with the URL and credentials, if there is one already.
+    async fn try_add_subscriber(
+        &self,
+        url: &str,
+        credentials: Option<&Credentials>,
+        target: &Target,
+    ) -> Result<bool>;
+
+    /// Unsubscribe the target from the feeds with the URL, whichever credentials they use.
+    async fn try_del_subscriber(&self, url: &str, target: &Target) -> Result<bool>;
+
+    /// Flip whether the user wants to be notified about edited entries in the feed.
+    ///
+    /// Returns the new setting, or `None` if the user is not subscribed to the feed.
+    async fn toggle_notify_updates(&self, url: &str, target: &Target) -> Result<Option<bool>>;
+
+    async fn get_subscribers(&self, url: &str) -> Result<Vec<Subscriber>>;
+
+    /// Everyone subscribed through Telegram to at least one feed.
+    async fn get_chat_subscribers(&self) -> Result<Vec<Target>>;
+
+    /// How many subscriptions the Telegram user owns, whatever they deliver to.
+    async fn count_subscriptions(&self, owner: i64) -> Result<usize>;
+
+    async fn get_user_feeds(&self, target: &Target) -> Result<Vec<String>>;
+
+    /// The webhooks the Telegram user added, as `(feed URL, hook URL)`.
+    async fn get_user_hooks(&self, owner: i64) -> Result<Vec<(String, String)>>;
+
+    /// Set (or clear) the feed's `/interval`. Returns `false` if there is no such feed.
+    async fn set_interval(&self, feed_id: i64, interval: Option<i64>) -> Result<bool>;
+
+    /// Flip whether the feed's caching headers are ignored, returning the new setting.
+    async fn toggle_ignore_cache(&self, feed_id: i64) -> Result<Option<bool>>;
+
+    async fn get_request_options(&self, feed_id: i64) -> Result<Option<RequestOptions>>;
+
+    /// Replace the feed's request options, which are used from its next check on.
+    async fn set_request_options(&self, feed_id: i64, options: &RequestOptions) -> Result<()>;
+
+    async fn get_websub(&self, feed_id: i64) -> Result<Option<Subscription>>;
+
+    async fn set_websub_lease(&self, feed_id: i64, lease_until: i64) -> Result<()>;
+
+    async fn mark_websub_requested(&self, feed_id: i64, requested: i64) -> Result<()>;
+
+    /// Subscriptions whose lease ends before `until`, and that were not requested after `retry_before`.
+    async fn websub_to_renew(&self, until: i64, retry_before: i64) -> Result<Vec<Subscription>>;
+
+    async fn add_to_digest(&self, address: &str, item: &Item) -> Result<()>;
+
+    /// Every pending digest, with its items in the order they were added.
+    async fn load_digests(&self) -> Result<Vec<Digest>>;
+
+    /// Forget the items of a digest that was sent. Items added since are kept.
+    async fn clear_digest(&self, digest: &Digest) -> Result<()>;
+
+    /// Ban the Telegram user. Returns `false` if they already were.
+    async fn ban(&self, user: i64, reason: Option<&str>) -> Result<bool>;
+
+    /// Lift the ban on the Telegram user. Returns `false` if they weren't banned.
+    async fn unban(&self, user: i64) -> Result<bool>;
+
+    async fn is_banned(&self, user: i64) -> Result<bool>;
+
+    /// Every banned user as `(user, reason, banned_at)`.
+    async fn get_bans(&self) -> Result<Vec<(i64, Option<String>, i64)>>;
+
+    /// Totals for the admins: users, feeds, entries, deliveries today and feeds failing to be checked.
+    async fn totals(&self) -> Result<Totals>;
+
+    /// Make sure the storage can still be written to, without changing anything.
+    async fn check_writable(&self) -> Result<()>;
+}
+
+impl From<sqlite::Error> for Error {
+    fn from(e: sqlite::Error) -> Self {
+        Self::SqliteError(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SqliteError(e) => write!(f, "database error: {}", e),
+            Self::NoVaultError => write!(f, "no key to encrypt the credentials with"),
+            Self::MissingVersionError => write!(f, "the database has no version"),
+            Self::TooNewError(version) => write!(
+                f,
+                "the database is at version {}, but this build only knows up to {}",
+                version,
+                crate::db::VERSION
+            ),
+            Self::TooOldError(version) => write!(
+                f,
+                "the database is at version {}, run `migrate` to upgrade it to {}",
+                version,
+                crate::db::VERSION
+            ),
+            Self::UnavailableError(e) => write!(f, "database unavailable: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::feed::Fingerprint;
+    use crate::memory::MemoryStorage;
+    use grammers_client::types::chat::PackedChat;
+    use std::collections::HashSet;
+
+    fn chat(id: u8) -> Target {
+        Target::Telegram(PackedChat::from_bytes(&[2, id, 0, 0, 0, 0]).unwrap())
+    }
+
+    /// A feed as fetched for the first time, with a single entry.
+    fn fetched(url: &str, target: Target) -> Feed {
+        let fingerprint = Fingerprint {
+            title: Some("Entry".to_string()),
+            link: None,
+            updated: None,
+            hash: Some("1".to_string()),
+        };
+        Feed {
+            users: vec![Subscriber {
+                target,
+                notify_updates: false,
+            }],
+            seen_entries: vec![("1".to_string(), fingerprint)].into_iter().collect(),
+            present_entries: vec!["1".to_string()].into_iter().collect(),
+            ..Feed::for_test(url)
+        }
+    }
+
+    async fn check_storage(db: &dyn Storage) {
+        let url = "https://example.com/atom.xml";
+        let feed_id = db.add_feed(&fetched(url, chat(1))).await.unwrap();
+        assert!(db.try_add_subscriber(url, None, &chat(2)).await.unwrap());
+        assert!(!db
+            .try_add_subscriber("https://example.com/rss", None, &chat(2))
+            .await
+            .unwrap());
+        assert!(db.feed_ids(url, Some(&chat(3))).await.unwrap().is_empty());
+        assert_eq!(db.count_subscriptions(chat(2).owner()).await.unwrap(), 1);
+        assert_eq!(db.get_user_feeds(&chat(2)).await.unwrap(), vec![url]);
+
+        let mut feeds = db.load_feeds().await.unwrap();
+        assert_eq!(feeds.len(), 1);
+        let mut feed = feeds.remove(0);
+        assert_eq!(feed.id, feed_id);
+        assert!(db.load_feed_state(&mut feed).await.unwrap());
+        assert_eq!(feed.users.len(), 2);
+        assert_eq!(feed.seen_entries.len(), 1);
+        assert!(feed.present_entries.contains("1"));
+
+        // Deliveries are only remembered until the entry is saved.
+        assert!(!db.was_delivered(feed_id, "2", "2", &chat(1)).await.unwrap());
+        db.mark_delivered(feed_id, "2", "2", &chat(1))
+            .await
+            .unwrap();
+        assert!(db.was_delivered(feed_id, "2", "2", &chat(1)).await.unwrap());
+        feed.seen_entries.insert(
+            "2".to_string(),
+            Fingerprint {
+                title: None,
+                link: None,
+                updated: None,
+                hash: Some("2".to_string()),
+            },
+        );
+        feed.present_entries = vec!["2".to_string()].into_iter().collect::<HashSet<_>>();
+        db.update_feed(&feed).await.unwrap();
+        assert!(!db.was_delivered(feed_id, "2", "2", &chat(1)).await.unwrap());
+
+        // Entries found under a new ID are moved there, rather than left behind.
+        let fingerprint = feed.seen_entries.remove("2").unwrap();
+        feed.seen_entries.insert("3".to_string(), fingerprint);
+        feed.present_entries = vec!["3".to_string()].into_iter().collect();
+        feed.renamed_entries = vec![("2".to_string(), "3".to_string())];
+        db.update_feed(&feed).await.unwrap();
+        assert!(db.load_feed_state(&mut feed).await.unwrap());
+        assert!(!feed.seen_entries.contains_key("2"));
+        assert!(feed.seen_entries.contains_key("3"));
+
+        assert_eq!(
+            db.toggle_notify_updates(url, &chat(1)).await.unwrap(),
+            Some(true)
+        );
+        assert_eq!(db.toggle_notify_updates(url, &chat(3)).await.unwrap(), None);
+        assert!(db.set_interval(feed_id, Some(300)).await.unwrap());
+        assert_eq!(db.toggle_ignore_cache(feed_id).await.unwrap(), Some(true));
+        assert!(db.load_feed_state(&mut feed).await.unwrap());
+        assert_eq!((feed.interval, feed.ignore_cache), (Some(300), true));
+
+        db.record_check(feed_id, Some("timed out")).await.unwrap();
+        assert_eq!(
+            db.totals().await.unwrap(),
+            Totals {
+                users: 2,
+                feeds: 1,
+                entries: 2,
+                deliveries_today: 1,
+                failing_feeds: 1,
+            }
+        );
+
+        assert!(db.ban(1, Some("spam")).await.unwrap());
+        assert!(!db.ban(1, None).await.unwrap());
+        assert!(db.is_banned(1).await.unwrap());
+        assert_eq!(db.get_bans().await.unwrap()[0].1.as_deref(), Some("spam"));
+        assert!(db.unban(1).await.unwrap());
+
+        // Hooks belong to whoever added them, and are removed without their secret.
+        let hook = |owner, secret: Option<&str>| Target::Webhook {
+            url: "https://example.com/hook".to_string(),
+            secret: secret.map(String::from),
+            owner,
+        };
+        assert!(db
+            .try_add_subscriber(url, None, &hook(7, Some("secret")))
+            .await
+            .unwrap());
+        assert_eq!(
+            db.get_user_hooks(7).await.unwrap(),
+            vec![(url.to_string(), "https://example.com/hook".to_string())]
+        );
+        assert!(db.get_user_hooks(8).await.unwrap().is_empty());
+        assert_eq!(db.count_subscriptions(7).await.unwrap(), 1);
+        assert!(db.load_feed_state(&mut feed).await.unwrap());
+        assert!(feed
+            .users
+            .iter()
+            .any(|sub| sub.target.secret() == Some("secret")));
+        assert!(!db.try_del_subscriber(url, &hook(8, None)).await.unwrap());
+        assert!(db.try_del_subscriber(url, &hook(7, None)).await.unwrap());
+
+        assert!(db.try_del_subscriber(url, &chat(1)).await.unwrap());
+        assert!(db.try_del_subscriber(url, &chat(2)).await.unwrap());
+        assert!(!db.load_feed_state(&mut feed).await.unwrap());
+        db.cleanup_feeds().await.unwrap();
+        assert!(db.load_feeds().await.unwrap().is_empty());
+        assert!(!db.remove_feed(url).await.unwrap());
+        db.check_writable().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn check_storages_agree() {
+        check_storage(&Database::new(":memory:").unwrap()).await;
+        check_storage(&MemoryStorage::default()).await;
+    }
+}
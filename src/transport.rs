@@ -0,0 +1,210 @@
+use async_trait::async_trait;
+use grammers_client::client::chats::InvocationError;
+use grammers_client::types::chat::PackedChat;
+use grammers_client::types::InputMessage;
+use grammers_client::Client;
+use std::fmt;
+use std::io::Cursor;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The user blocked the bot, so there is no point in trying again.
+    Blocked,
+    /// Too many messages were sent, and this many seconds need to pass before sending more.
+    FloodWait(u32),
+    /// The messenger refused the request for some other reason.
+    Rejected(String),
+    /// The request could not be made at all (network problems, disconnections…).
+    Other(String),
+}
+
+/// A message that was sent and can later be edited.
+#[derive(Debug, Clone, Copy)]
+pub struct Sent {
+    pub chat: PackedChat,
+    pub id: i32,
+}
+
+/// Everything the bot needs from the messenger it talks to users through.
+#[async_trait]
+pub trait ChatTransport: Send + Sync {
+    async fn send(&self, chat: PackedChat, text: &str) -> Result<Sent, Error>;
+
+    async fn edit(&self, sent: &Sent, text: &str) -> Result<(), Error>;
+
+    async fn send_photo(
+        &self,
+        chat: PackedChat,
+        photo: Vec<u8>,
+        caption: &str,
+    ) -> Result<Sent, Error>;
+
+    /// Delete a message someone else sent, so that what it said doesn't linger in the chat.
+    async fn delete(&self, chat: PackedChat, id: i32) -> Result<(), Error>;
+}
+
+/// A message as received from any messenger.
+#[derive(Debug, Clone)]
+pub struct Incoming {
+    pub chat: PackedChat,
+    pub sender: PackedChat,
+    pub id: i32,
+    pub text: String,
+}
+
+pub struct Telegram(pub Client);
+
+#[async_trait]
+impl ChatTransport for Telegram {
+    async fn send(&self, chat: PackedChat, text: &str) -> Result<Sent, Error> {
+        let message = self.0.send_message(chat, text).await?;
+        Ok(Sent {
+            chat,
+            id: message.id(),
+        })
+    }
+
+    async fn edit(&self, sent: &Sent, text: &str) -> Result<(), Error> {
+        Ok(self.0.edit_message(sent.chat, sent.id, text).await?)
+    }
+
+    async fn send_photo(
+        &self,
+        chat: PackedChat,
+        photo: Vec<u8>,
+        caption: &str,
+    ) -> Result<Sent, Error> {
+        let size = photo.len();
+        let uploaded = self
+            .0
+            .upload_stream(&mut Cursor::new(photo), size, "photo.jpg".to_string())
+            .await
+            .map_err(|e| Error::Other(e.to_string()))?;
+
+        let message = self
+            .0
+            .send_message(chat, InputMessage::text(caption).photo(uploaded))
+            .await?;
+        Ok(Sent {
+            chat,
+            id: message.id(),
+        })
+    }
+
+    async fn delete(&self, chat: PackedChat, id: i32) -> Result<(), Error> {
+        self.0.delete_messages(chat, &[id]).await?;
+        Ok(())
+    }
+}
+
+/// Transport that only remembers what would have been sent, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Recorder {
+    pub messages: std::sync::Mutex<Vec<Recorded>>,
+    /// Identifiers of the incoming messages that were deleted.
+    pub deleted: std::sync::Mutex<Vec<i32>>,
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub chat: PackedChat,
+    pub text: String,
+    pub photo: bool,
+}
+
+#[cfg(test)]
+impl Recorder {
+    pub fn texts(&self) -> Vec<String> {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| m.text.clone())
+            .collect()
+    }
+
+    fn record(&self, chat: PackedChat, text: &str, photo: bool) -> Sent {
+        let mut messages = self.messages.lock().unwrap();
+        messages.push(Recorded {
+            chat,
+            text: text.to_string(),
+            photo,
+        });
+        Sent {
+            chat,
+            id: messages.len() as i32 - 1,
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl ChatTransport for Recorder {
+    async fn send(&self, chat: PackedChat, text: &str) -> Result<Sent, Error> {
+        Ok(self.record(chat, text, false))
+    }
+
+    async fn edit(&self, sent: &Sent, text: &str) -> Result<(), Error> {
+        match self.messages.lock().unwrap().get_mut(sent.id as usize) {
+            Some(message) => {
+                message.text = text.to_string();
+                Ok(())
+            }
+            None => Err(Error::Rejected("MESSAGE_ID_INVALID".to_string())),
+        }
+    }
+
+    async fn send_photo(
+        &self,
+        chat: PackedChat,
+        _photo: Vec<u8>,
+        caption: &str,
+    ) -> Result<Sent, Error> {
+        Ok(self.record(chat, caption, true))
+    }
+
+    async fn delete(&self, _chat: PackedChat, id: i32) -> Result<(), Error> {
+        self.deleted.lock().unwrap().push(id);
+        Ok(())
+    }
+}
+
+impl From<InvocationError> for Error {
+    fn from(e: InvocationError) -> Self {
+        match e {
+            InvocationError::Rpc(rpc) if rpc.name == "USER_IS_BLOCKED" => Self::Blocked,
+            InvocationError::Rpc(rpc) if rpc.name == "FLOOD_WAIT" => {
+                Self::FloodWait(rpc.value.unwrap_or(0))
+            }
+            InvocationError::Rpc(rpc) => Self::Rejected(rpc.to_string()),
+            e => Self::Other(e.to_string()),
+        }
+    }
+}
+
+impl Error {
+    /// Short name of the variant, to group errors by.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Blocked => "blocked",
+            Self::FloodWait(_) => "flood_wait",
+            Self::Rejected(_) => "rejected",
+            Self::Other(_) => "other",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Blocked => write!(f, "blocked by the user"),
+            Self::FloodWait(secs) => write!(f, "flood wait of {} seconds", secs),
+            Self::Rejected(e) => write!(f, "request rejected: {}", e),
+            Self::Other(e) => write!(f, "request failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,212 @@
+use crate::feed::Change;
+use crate::net;
+use crate::transport::Error;
+use hmac::{Hmac, Mac, NewMac};
+use once_cell::sync::Lazy;
+use reqwest::{redirect, Client, StatusCode};
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// How many times to try delivering to a webhook before giving up.
+const ATTEMPTS: u32 = 3;
+
+/// How long to wait before the first retry. Every retry waits four times longer than the last.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Client for the webhooks, which doesn't follow redirects, as they could lead anywhere.
+static CLIENT: Lazy<Client> = Lazy::new(|| {
+    Client::builder()
+        .redirect(redirect::Policy::none())
+        .build()
+        .expect("failed to build the webhook client")
+});
+
+/// Header with the hex HMAC-SHA256 of the body, when the webhook has a secret.
+static SIGNATURE_HEADER: &str = "X-Srsrssrs-Signature";
+
+fn payload(feed_url: &str, change: &Change) -> serde_json::Value {
+    let entry = &change.entry;
+    serde_json::json!({
+        "feed": {
+            "url": feed_url,
+        },
+        "entry": {
+            "id": entry.id,
+            "title": entry.title.as_ref().map(|t| t.content.as_str()),
+            "link": entry.links.iter().next().map(|link| link.href.as_str()),
+            "summary": entry.summary.as_ref().map(|t| t.content.as_str()),
+            "published": entry.published.map(|d| d.to_rfc3339()),
+            "updated": entry.updated.map(|d| d.to_rfc3339()),
+        },
+        "edited": change.updated,
+    })
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    // HMAC accepts keys of any length, so this can't fail.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body);
+    let hex = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("sha256={}", hex)
+}
+
+/// `POST` the change to the webhook, retrying on network and server errors.
+///
+/// A `410 Gone` is treated like a user blocking the bot. Other client errors are not retried, and
+/// neither are webhooks which no longer point to a public address, or to this machine unless
+/// `allow_loopback`.
+pub async fn deliver(
+    url: &str,
+    secret: Option<&str>,
+    feed_url: &str,
+    change: &Change,
+    allow_loopback: bool,
+) -> Result<(), Error> {
+    let body = payload(feed_url, change).to_string();
+    let mut delay = RETRY_DELAY;
+    let mut last_err = None;
+
+    for attempt in 0..ATTEMPTS {
+        if attempt != 0 {
+            sleep(delay).await;
+            delay *= 4;
+        }
+        if !net::resolves_publicly(url, allow_loopback).await {
+            return Err(Error::Rejected("not a public address".to_string()));
+        }
+
+        let mut request = CLIENT
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        if let Some(secret) = secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, body.as_bytes()));
+        }
+
+        match request.send().await {
+            Ok(resp) if resp.status().is_success() => return Ok(()),
+            Ok(resp) if resp.status() == StatusCode::GONE => return Err(Error::Blocked),
+            Ok(resp) if resp.status().is_client_error() => {
+                return Err(Error::Rejected(resp.status().to_string()))
+            }
+            Ok(resp) => last_err = Some(resp.status().to_string()),
+            Err(mut e) => {
+                // The error names the URL, only the host of which is safe to log (see
+                // `Target::redacted`).
+                if let Some(url) = e.url_mut() {
+                    let _ = url.set_username("");
+                    let _ = url.set_password(None);
+                    url.set_path("");
+                    url.set_query(None);
+                }
+                last_err = Some(e.to_string());
+            }
+        }
+    }
+
+    Err(Error::Other(last_err.unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::{Arc, Mutex};
+
+    static ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Test feed</title>
+  <id>urn:test</id>
+  <updated>2021-05-01T00:00:00Z</updated>
+  <entry>
+    <title>Entry</title>
+    <id>urn:test:1</id>
+    <link href="https://example.com/1"/>
+    <updated>2021-05-01T00:00:00Z</updated>
+  </entry>
+</feed>"#;
+
+    fn change() -> Change {
+        Change {
+            entry: feed_rs::parser::parse(ATOM.as_bytes())
+                .unwrap()
+                .entries
+                .remove(0),
+            updated: false,
+            previous: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn check_webhook_delivery_signed() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let make_svc = {
+            let received = received.clone();
+            make_service_fn(move |_| {
+                let received = received.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let received = received.clone();
+                        async move {
+                            let signature = req.headers()[SIGNATURE_HEADER]
+                                .to_str()
+                                .unwrap()
+                                .to_string();
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            received.lock().unwrap().push((signature, body));
+                            Ok::<_, Infallible>(Response::new(Body::empty()))
+                        }
+                    }))
+                }
+            })
+        };
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+
+        deliver(
+            &url,
+            Some("secret"),
+            "https://example.com/atom.xml",
+            &change(),
+            true,
+        )
+        .await
+        .unwrap();
+
+        let received = received.lock().unwrap();
+        let (signature, body) = &received[0];
+        assert_eq!(signature, &sign("secret", body));
+
+        let json = serde_json::from_slice::<serde_json::Value>(body).unwrap();
+        assert_eq!(json["feed"]["url"], "https://example.com/atom.xml");
+        assert_eq!(json["entry"]["link"], "https://example.com/1");
+        assert_eq!(json["edited"], false);
+    }
+    #[tokio::test]
+    async fn check_loopback_refused() {
+        for url in [
+            "http://127.0.0.1/hook",
+            "http://[::1]/hook",
+            "http://0.0.0.0/hook",
+            "http://localhost/hook",
+        ]
+        .iter()
+        {
+            let result = deliver(url, None, "https://example.com/atom.xml", &change(), false).await;
+            assert!(
+                matches!(result, Err(Error::Rejected(_))),
+                "{} is this machine",
+                url
+            );
+        }
+    }
+}
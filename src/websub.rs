@@ -0,0 +1,546 @@
+use crate::net;
+use crate::storage::{self, Storage};
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use hyper::body::{Bytes, HttpBody};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use once_cell::sync::Lazy;
+use reqwest::{redirect, Client, Url};
+use sha1::Sha1;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// How long to ask hubs to keep our subscriptions for.
+const LEASE_SECONDS: i64 = 10 * 24 * 60 * 60;
+
+/// The longest lease a hub is believed to grant, as it's up to the hub to say.
+const MAX_LEASE_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+/// Renew subscriptions when they have less than this many seconds left.
+const RENEW_MARGIN: i64 = 24 * 60 * 60;
+
+/// How many seconds to give a hub to verify a subscription before asking again.
+const RETRY_DELAY: i64 = 60 * 60;
+
+/// The largest content a hub may push. Feeds are rarely more than a few hundred kilobytes.
+const MAX_PUSH_SIZE: usize = 5 * 1024 * 1024;
+
+/// How long to sleep before checking which subscriptions need renewing.
+const RENEW_CHECK_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// Client for the hubs, which doesn't follow redirects, as they could lead anywhere.
+static CLIENT: Lazy<Client> = Lazy::new(|| {
+    Client::builder()
+        .redirect(redirect::Policy::none())
+        .build()
+        .expect("failed to build the websub client")
+});
+
+/// The hub a feed advertises, and the topic (URL) it should be subscribed to with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hub {
+    pub hub: String,
+    pub topic: String,
+}
+
+/// A stored subscription to a hub.
+#[derive(Debug)]
+pub struct Subscription {
+    pub feed_id: i64,
+    pub url: String,
+    pub hub: String,
+    pub topic: String,
+    pub secret: String,
+    /// When we last asked the hub to subscribe, or 0 if there's no request waiting to be verified.
+    pub requested: i64,
+}
+
+/// Content pushed by a hub, to be processed by `handle_feed` as if it had been fetched.
+pub struct Push {
+    pub feed_id: i64,
+    pub body: Bytes,
+}
+
+struct State {
+    db: Arc<dyn Storage>,
+    pushes: mpsc::UnboundedSender<Push>,
+}
+
+impl Hub {
+    /// The hub the feed advertises, unless it's somewhere it shouldn't be (see `is_allowed`).
+    pub fn find(feed: &feed_rs::model::Feed, url: &str) -> Option<Self> {
+        let rel = |name| {
+            feed.links
+                .iter()
+                .find(|link| link.rel.as_deref() == Some(name))
+                .map(|link| link.href.clone())
+        };
+
+        let hub = rel("hub")?;
+        if !is_allowed(&hub) {
+            return None;
+        }
+        Some(Self {
+            hub,
+            topic: rel("self").unwrap_or_else(|| url.to_string()),
+        })
+    }
+}
+
+/// Whether the hub may be subscribed to. Whoever writes the feed picks it, so it must be public,
+/// and on `https` so that the secret we send it can't be read on the way.
+fn is_allowed(hub: &str) -> bool {
+    Url::parse(hub).map_or(false, |url| url.scheme() == "https") && net::is_public_url(hub, false)
+}
+
+pub fn new_secret() -> String {
+    rand::random::<[u8; 20]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Check the `X-Hub-Signature` of a pushed body against the subscription's secret.
+fn verify_signature(secret: &str, signature: Option<&str>, body: &[u8]) -> bool {
+    let mut parts = match signature {
+        Some(signature) => signature.splitn(2, '='),
+        None => return false,
+    };
+    let method = parts.next().unwrap_or("");
+    let tag = match parts.next().and_then(decode_hex) {
+        Some(tag) => tag,
+        None => return false,
+    };
+
+    let valid = match method {
+        "sha1" => Hmac::<Sha1>::new_from_slice(secret.as_bytes()).map(|mut mac| {
+            mac.update(body);
+            mac.verify(&tag).is_ok()
+        }),
+        "sha256" => Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map(|mut mac| {
+            mac.update(body);
+            mac.verify(&tag).is_ok()
+        }),
+        _ => return false,
+    };
+    valid.unwrap_or(false)
+}
+
+/// Read the whole body, or `None` if it's larger than `limit`, without buffering more than that.
+async fn read_body(mut body: Body, limit: usize) -> Result<Option<Bytes>, hyper::Error> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes.into()))
+}
+
+fn callback_url(base_url: &str, feed_id: i64) -> String {
+    format!("{}/{}", base_url.trim_end_matches('/'), feed_id)
+}
+
+fn respond(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    response
+}
+
+async fn subscribe(base_url: &str, sub: &Subscription) -> Result<(), reqwest::Error> {
+    let callback = callback_url(base_url, sub.feed_id);
+    let lease = LEASE_SECONDS.to_string();
+    CLIENT
+        .post(&sub.hub)
+        .form(&[
+            ("hub.callback", callback.as_str()),
+            ("hub.mode", "subscribe"),
+            ("hub.topic", sub.topic.as_str()),
+            ("hub.secret", sub.secret.as_str()),
+            ("hub.lease_seconds", lease.as_str()),
+        ])
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn renew_subscriptions(db: &dyn Storage, base_url: &str) {
+    loop {
+        let now = Utc::now().timestamp();
+        match db
+            .websub_to_renew(now + RENEW_MARGIN, now - RETRY_DELAY)
+            .await
+        {
+            Ok(subs) => {
+                for sub in subs {
+                    if let Err(e) = db.mark_websub_requested(sub.feed_id, now).await {
+                        warn!(feed.url = %sub.url, error = %e, "failed to mark websub request");
+                        continue;
+                    }
+                    // Saved before hubs were checked, or now resolving somewhere else.
+                    if !is_allowed(&sub.hub) || !net::resolves_publicly(&sub.hub, false).await {
+                        warn!(
+                            feed.url = %sub.url,
+                            hub = %sub.hub,
+                            "refusing to subscribe to websub hub"
+                        );
+                        continue;
+                    }
+                    match subscribe(base_url, &sub).await {
+                        Ok(_) => info!(feed.url = %sub.url, "requested websub subscription"),
+                        Err(e) => warn!(
+                            feed.url = %sub.url,
+                            hub = %sub.hub,
+                            http.status = e.status().map_or(0, |s| s.as_u16()),
+                            error = %e,
+                            "failed to subscribe to websub hub"
+                        ),
+                    }
+                }
+            }
+            Err(e) => warn!(error = %e, "failed to load websub subscriptions to renew"),
+        }
+        sleep(RENEW_CHECK_DELAY).await;
+    }
+}
+
+/// Answer the hub's intent verification, which is how leases are confirmed (or denied).
+async fn verify_intent(
+    state: &State,
+    feed_id: i64,
+    query: Option<&str>,
+) -> storage::Result<Response<Body>> {
+    let params = form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .into_owned()
+        .collect::<HashMap<_, _>>();
+    let param = |key: &str| params.get(key).map(String::as_str).unwrap_or("");
+
+    let now = Utc::now().timestamp();
+    let sub = state.db.get_websub(feed_id).await?;
+    let topic_matches = sub
+        .as_ref()
+        .map_or(false, |sub| sub.topic == param("hub.topic"));
+    // Anyone can send this, so it's only believed while the hub still has our request. Otherwise
+    // they could make us think the feed is pushed, and poll it much less.
+    let pending = sub.as_ref().map_or(false, |sub| {
+        sub.requested != 0 && (0..=RETRY_DELAY).contains(&(now - sub.requested))
+    });
+
+    Ok(match param("hub.mode") {
+        "subscribe" if topic_matches && pending => {
+            let lease = param("hub.lease_seconds")
+                .parse::<i64>()
+                .unwrap_or(LEASE_SECONDS)
+                .clamp(0, MAX_LEASE_SECONDS);
+            let lease_until = now.checked_add(lease).unwrap_or(now);
+            state.db.set_websub_lease(feed_id, lease_until).await?;
+            state.db.mark_websub_requested(feed_id, 0).await?;
+            info!(topic = param("hub.topic"), "websub subscription verified");
+            respond(StatusCode::OK, param("hub.challenge").to_string())
+        }
+        // We never unsubscribe on purpose, but the feed may have been deleted since.
+        "unsubscribe" if !topic_matches => {
+            respond(StatusCode::OK, param("hub.challenge").to_string())
+        }
+        "denied" => {
+            warn!(
+                topic = param("hub.topic"),
+                reason = param("hub.reason"),
+                "websub subscription denied"
+            );
+            if topic_matches {
+                state.db.set_websub_lease(feed_id, 0).await?;
+            }
+            respond(StatusCode::OK, Body::empty())
+        }
+        _ => respond(StatusCode::NOT_FOUND, Body::empty()),
+    })
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    // Only the last segment is looked at so that the endpoint can live behind a reverse proxy.
+    let feed_id = match req.uri().path().rsplit('/').next().map(str::parse::<i64>) {
+        Some(Ok(id)) => id,
+        _ => return Ok(respond(StatusCode::NOT_FOUND, Body::empty())),
+    };
+
+    let result = match req.method() {
+        &Method::GET => verify_intent(&state, feed_id, req.uri().query()).await,
+        &Method::POST => {
+            let signature = req
+                .headers()
+                .get("x-hub-signature")
+                .and_then(|v| v.to_str().ok())
+                .map(String::from);
+            let too_large = req
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+                .map_or(false, |length| length > MAX_PUSH_SIZE);
+            if too_large {
+                return Ok(respond(StatusCode::PAYLOAD_TOO_LARGE, Body::empty()));
+            }
+            let body = match read_body(req.into_body(), MAX_PUSH_SIZE).await {
+                Ok(Some(body)) => body,
+                Ok(None) => return Ok(respond(StatusCode::PAYLOAD_TOO_LARGE, Body::empty())),
+                Err(_) => return Ok(respond(StatusCode::BAD_REQUEST, Body::empty())),
+            };
+
+            state.db.get_websub(feed_id).await.map(|sub| match sub {
+                // Telling the hub the subscription is gone should make it stop pushing.
+                None => respond(StatusCode::GONE, Body::empty()),
+                Some(sub) => {
+                    // Per spec, content with a bad signature is acknowledged but ignored.
+                    if verify_signature(&sub.secret, signature.as_deref(), &body) {
+                        let _ = state.pushes.send(Push {
+                            feed_id: sub.feed_id,
+                            body,
+                        });
+                    } else {
+                        warn!(feed.url = %sub.url, "ignoring websub push with bad signature");
+                    }
+                    respond(StatusCode::ACCEPTED, Body::empty())
+                }
+            })
+        }
+        _ => Ok(respond(StatusCode::METHOD_NOT_ALLOWED, Body::empty())),
+    };
+
+    Ok(match result {
+        Ok(mut response) => {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+            response
+        }
+        Err(e) => {
+            warn!(feed.id = feed_id, error = %e, "failed to handle websub request");
+            respond(StatusCode::INTERNAL_SERVER_ERROR, Body::empty())
+        }
+    })
+}
+
+/// Serve the WebSub callback endpoint on `addr`, and keep the subscriptions to hubs alive.
+///
+/// `base_url` is the public URL under which `addr` is reachable by the hubs.
+pub async fn run(
+    db: Arc<dyn Storage>,
+    addr: SocketAddr,
+    base_url: &str,
+    pushes: mpsc::UnboundedSender<Push>,
+) -> Result<(), hyper::Error> {
+    let state = Arc::new(State {
+        db: db.clone(),
+        pushes,
+    });
+    let make_svc = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+    });
+
+    tokio::select! {
+        r = Server::bind(&addr).serve(make_svc) => r,
+        _ = renew_subscriptions(&db, base_url) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feed::{Feed, Subscriber};
+    use crate::memory::MemoryStorage;
+    use crate::target::Target;
+
+    static TOPIC: &str = "https://example.com/atom.xml";
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let tag = mac.finalize().into_bytes();
+        format!(
+            "sha256={}",
+            tag.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+        )
+    }
+
+    /// A feed advertising a hub, as it would be after being fetched.
+    fn pushed_feed() -> Feed {
+        Feed {
+            users: vec![Subscriber {
+                target: Target::Matrix {
+                    room: "!room:example.com".to_string(),
+                },
+                notify_updates: false,
+            }],
+            hub: Some(Hub {
+                hub: "https://hub.example.com".to_string(),
+                topic: TOPIC.to_string(),
+            }),
+            ..Feed::for_test(TOPIC)
+        }
+    }
+
+    async fn push_until(db: &dyn Storage, feed_id: i64) -> i64 {
+        let mut feed = pushed_feed();
+        feed.id = feed_id;
+        db.load_feed_state(&mut feed).await.unwrap();
+        feed.push_until
+    }
+
+    /// Serve the callback endpoint on a local port, returning its base URL.
+    fn serve(state: Arc<State>) -> String {
+        let make_svc = make_service_fn(move |_| {
+            let state = state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        url
+    }
+
+    #[test]
+    fn check_signature_verification() {
+        let body = b"<feed/>";
+        let signature = sign("secret", body);
+        assert!(verify_signature("secret", Some(&signature), body));
+        assert!(!verify_signature("other", Some(&signature), body));
+        assert!(!verify_signature(
+            "secret",
+            Some(&signature),
+            b"<feed></feed>"
+        ));
+        assert!(!verify_signature("secret", None, body));
+        assert!(!verify_signature(
+            "secret",
+            Some(&signature.replace("sha256", "md5")),
+            body
+        ));
+        assert!(!verify_signature("secret", Some("sha256=zz"), body));
+    }
+
+    #[test]
+    fn check_hub_must_be_public_https() {
+        let find = |hub: &str| {
+            let xml = format!(
+                r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Test feed</title>
+  <id>urn:test</id>
+  <updated>2021-05-01T00:00:00Z</updated>
+  <link rel="hub" href="{}"/>
+</feed>"#,
+                hub
+            );
+            let feed = feed_rs::parser::parse(xml.as_bytes()).unwrap();
+            Hub::find(&feed, TOPIC).map(|hub| hub.hub)
+        };
+
+        assert_eq!(
+            find("https://hub.example.com/"),
+            Some("https://hub.example.com/".to_string())
+        );
+        for hub in [
+            "http://hub.example.com/",
+            "/hub",
+            "hub.example.com",
+            "https://127.0.0.1/",
+            "https://[::1]/",
+            "https://localhost/",
+            "https://169.254.169.254/",
+        ]
+        .iter()
+        {
+            assert_eq!(find(hub), None, "{} is not allowed", hub);
+        }
+    }
+
+    #[tokio::test]
+    async fn check_intent_verification() {
+        let db: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let feed_id = db.add_feed(&pushed_feed()).await.unwrap();
+        let (pushes, mut pushed) = mpsc::unbounded_channel();
+        let url = serve(Arc::new(State {
+            db: Arc::clone(&db),
+            pushes,
+        }));
+        let http = reqwest::Client::new();
+        let verify = |topic: &str| {
+            http.get(&format!("{}/{}", url, feed_id))
+                .query(&[
+                    ("hub.mode", "subscribe"),
+                    ("hub.topic", topic),
+                    ("hub.challenge", "challenge"),
+                    ("hub.lease_seconds", "9223372036854775807"),
+                ])
+                .send()
+        };
+
+        // Nothing was requested, so there's nothing to confirm.
+        assert_eq!(verify(TOPIC).await.unwrap().status(), 404);
+        assert_eq!(push_until(&*db, feed_id).await, 0);
+
+        let now = Utc::now().timestamp();
+        db.mark_websub_requested(feed_id, now).await.unwrap();
+        assert_eq!(
+            verify("https://example.com/other").await.unwrap().status(),
+            404
+        );
+        let resp = verify(TOPIC).await.unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.text().await.unwrap(), "challenge");
+        let lease_until = push_until(&*db, feed_id).await;
+        assert!(lease_until > now && lease_until <= now + MAX_LEASE_SECONDS + 1);
+        // The request was answered, so the same verification can't be replayed.
+        assert_eq!(verify(TOPIC).await.unwrap().status(), 404);
+
+        let secret = db.get_websub(feed_id).await.unwrap().unwrap().secret;
+        let post = |signature: String, body: &'static str| {
+            http.post(&format!("{}/{}", url, feed_id))
+                .header("x-hub-signature", signature)
+                .body(body)
+                .send()
+        };
+        let forged = "<feed><title>Forged</title></feed>";
+        let resp = post(sign("wrong", forged.as_bytes()), forged).await;
+        assert_eq!(resp.unwrap().status(), 202);
+        let body = "<feed/>";
+        let resp = post(sign(&secret, body.as_bytes()), body).await;
+        assert_eq!(resp.unwrap().status(), 202);
+        // Only the signed content made it through.
+        let push = pushed.recv().await.unwrap();
+        assert_eq!(
+            (push.feed_id, push.body.as_ref()),
+            (feed_id, body.as_bytes())
+        );
+    }
+
+    #[tokio::test]
+    async fn check_push_size_limit() {
+        let body = read_body(Body::from("<feed/>"), 7).await.unwrap();
+        assert_eq!(body.as_deref(), Some(&b"<feed/>"[..]));
+        let body = read_body(Body::from(vec![b' '; MAX_PUSH_SIZE + 1]), MAX_PUSH_SIZE);
+        assert!(body.await.unwrap().is_none());
+    }
+}